@@ -16,6 +16,22 @@ function action_status()
 	local e = {}
 	e.running = luci.sys.call("pidof pikpak-webdav >/dev/null") == 0
 	e.application = luci.sys.exec("pikpak-webdav --version")
+	if e.running then
+		local uci = require "luci.model.uci".cursor()
+		local host = uci:get_first("pikpak-webdav", "server", "host") or "127.0.0.1"
+		local port = uci:get_first("pikpak-webdav", "server", "port") or "9867"
+		if host == "0.0.0.0" then
+			host = "127.0.0.1"
+		end
+		-- the certificate is for the public name, not the address probed here
+		local url = "http://%s:%s/healthz" % {host, port}
+		local flags = ""
+		if (uci:get_first("pikpak-webdav", "server", "tls_cert") or "") ~= "" then
+			url = "https://%s:%s/healthz" % {host, port}
+			flags = "--no-check-certificate"
+		end
+		e.healthy = luci.sys.call("wget -q -T 2 %s -O /dev/null %s" % {flags, url}) == 0
+	end
 	luci.http.prepare_content("application/json")
 	luci.http.write_json(e)
 end
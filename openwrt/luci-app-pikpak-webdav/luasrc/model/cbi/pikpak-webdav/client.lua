@@ -34,6 +34,13 @@ port = e:option(Value, "port", translate("Port"))
 port.default = "9867"
 port.datatype = "port"
 
+tls_cert = e:option(Value, "tls_cert", translate("TLS Certificate"))
+tls_cert.description = translate("Certificate chain in PEM format, serves HTTPS together with the TLS key")
+tls_cert.datatype = "file"
+
+tls_key = e:option(Value, "tls_key", translate("TLS Key"))
+tls_key.datatype = "file"
+
 auth_user = e:option(Value, "auth_user", translate("Username"))
 auth_password = e:option(Value, "auth_password", translate("Password"))
 auth_password.password = true
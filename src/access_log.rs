@@ -0,0 +1,347 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use dav_server::body::Body;
+use hyper::body::{HttpBody, SizeHint};
+use hyper::{HeaderMap, Request};
+use serde::{Deserialize, Serialize};
+use time::format_description::{self, well_known::Rfc3339};
+use time::OffsetDateTime;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::error;
+
+/// Lines waiting for the writer before requests start to wait for it
+const QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Apache combined format followed by the quoted destination and the duration in seconds
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "combined" => Ok(Self::Combined),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown access log format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+    pub format: AccessLogFormat,
+    /// The file is rotated once it grows past this many bytes
+    pub max_size: u64,
+    /// Rotated files kept next to the current one as `<path>.1` and up
+    pub max_files: usize,
+}
+
+/// One served request
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    #[serde(skip)]
+    time: SystemTime,
+    client: Option<IpAddr>,
+    /// Authenticated user, none without auth or when it failed
+    user: Option<String>,
+    method: String,
+    /// Path and query as requested, link signatures left out
+    path: String,
+    #[serde(skip)]
+    protocol: String,
+    /// Destination header of COPY and MOVE
+    destination: Option<String>,
+    status: u16,
+    /// Response body bytes sent, less than its length if the client went away
+    bytes: u64,
+    /// Until the response body was sent or abandoned
+    #[serde(skip)]
+    duration: Duration,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+/// A signature in the log would let anyone reading it fetch the file.
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| if pair.starts_with("sign=") { "sign=-" } else { pair })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+impl Entry {
+    /// What is known of a request before it is handled.
+    pub fn new(req: &Request<hyper::Body>, client: Option<SocketAddr>) -> Self {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            time: SystemTime::now(),
+            client: client.map(|addr| addr.ip()),
+            user: None,
+            method: req.method().to_string(),
+            path: match req.uri().query() {
+                Some(query) => format!("{}?{}", req.uri().path(), redact_query(query)),
+                None => req.uri().path().to_string(),
+            },
+            protocol: format!("{:?}", req.version()),
+            destination: header("destination"),
+            status: 0,
+            bytes: 0,
+            duration: Duration::default(),
+            referer: header("referer"),
+            user_agent: header("user-agent"),
+        }
+    }
+
+    /// What is known once the response headers are ready.
+    pub fn respond(&mut self, user: Option<String>, status: u16) {
+        self.user = user;
+        self.status = status;
+    }
+
+    fn combined(&self) -> String {
+        let format = format_description::parse(
+            "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000",
+        )
+        .unwrap();
+        let time = OffsetDateTime::from(self.time)
+            .format(&format)
+            .unwrap_or_default();
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" \"{}\" {:.3}\n",
+            self.client.map(|c| c.to_string()).unwrap_or_else(|| "-".to_string()),
+            self.user.as_deref().map(escape).unwrap_or_else(|| "-".to_string()),
+            time,
+            self.method,
+            escape(&self.path),
+            self.protocol,
+            self.status,
+            self.bytes,
+            self.referer.as_deref().map(escape).unwrap_or_else(|| "-".to_string()),
+            self.user_agent.as_deref().map(escape).unwrap_or_else(|| "-".to_string()),
+            self.destination.as_deref().map(escape).unwrap_or_else(|| "-".to_string()),
+            self.duration.as_secs_f64(),
+        )
+    }
+
+    fn json(&self) -> String {
+        #[derive(Serialize)]
+        struct Line<'a> {
+            time: String,
+            #[serde(flatten)]
+            entry: &'a Entry,
+            duration: f64,
+        }
+        let line = Line {
+            time: OffsetDateTime::from(self.time)
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            entry: self,
+            duration: self.duration.as_secs_f64(),
+        };
+        let mut line = serde_json::to_string(&line).unwrap_or_default();
+        line.push('\n');
+        line
+    }
+}
+
+/// Keep quotes and control characters from breaking up a combined log line.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Response body that logs its request once it was sent completely, failed or
+/// was dropped because the client went away.
+pub struct LoggedBody {
+    inner: Body,
+    pending: Option<(AccessLog, Entry, Instant)>,
+}
+
+impl LoggedBody {
+    /// `inner` as is when there is nothing to log.
+    pub fn new(inner: Body, pending: Option<(AccessLog, Entry, Instant)>) -> Self {
+        Self { inner, pending }
+    }
+
+    fn finish(&mut self) {
+        if let Some((log, mut entry, started)) = self.pending.take() {
+            entry.duration = started.elapsed();
+            tokio::spawn(async move { log.log(entry).await });
+        }
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let res = Pin::new(&mut self.inner).poll_data(cx);
+        match &res {
+            Poll::Ready(Some(Ok(data))) => {
+                if let Some((_, entry, _)) = self.pending.as_mut() {
+                    entry.bytes += data.len() as u64;
+                }
+            }
+            Poll::Ready(_) => self.finish(),
+            Poll::Pending => {}
+        }
+        res
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Appends one line per request to a size rotated file, written in the background.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    tx: mpsc::Sender<String>,
+}
+
+impl AccessLog {
+    pub async fn open(config: AccessLogConfig) -> Result<Self> {
+        let writer = Writer::open(config.path, config.max_size, config.max_files).await?;
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(writer.run(rx));
+        Ok(Self {
+            format: config.format,
+            tx,
+        })
+    }
+
+    pub async fn log(&self, entry: Entry) {
+        let line = match self.format {
+            AccessLogFormat::Combined => entry.combined(),
+            AccessLogFormat::Json => entry.json(),
+        };
+        let _ = self.tx.send(line).await;
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+async fn open_append(path: &Path) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    options.mode(0o640);
+    options
+        .open(path)
+        .await
+        .with_context(|| format!("open access log {}", path.display()))
+}
+
+impl Writer {
+    async fn open(path: PathBuf, max_size: u64, max_files: usize) -> Result<Self> {
+        let file = open_append(&path).await?;
+        let size = file.metadata().await?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    async fn run(mut self, mut rx: mpsc::Receiver<String>) {
+        while let Some(line) = rx.recv().await {
+            if let Err(err) = self.write(line.as_bytes()).await {
+                error!(path = %self.path.display(), error = %err, "access log: write failed");
+            }
+        }
+    }
+
+    async fn write(&mut self, line: &[u8]) -> Result<()> {
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate().await?;
+        }
+        self.file.write_all(line).await?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    /// `<path>.1` becomes `<path>.2` and so on, the oldest falls off the end.
+    async fn rotate(&mut self) -> Result<()> {
+        self.file.flush().await?;
+        if self.max_files == 0 {
+            self.file.set_len(0).await?;
+            self.size = 0;
+            return Ok(());
+        }
+        let _ = tokio::fs::remove_file(self.rotated(self.max_files)).await;
+        for index in (1..self.max_files).rev() {
+            let _ = tokio::fs::rename(self.rotated(index), self.rotated(index + 1)).await;
+        }
+        tokio::fs::rename(&self.path, self.rotated(1)).await?;
+        self.file = open_append(&self.path).await?;
+        self.size = 0;
+        Ok(())
+    }
+}
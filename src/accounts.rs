@@ -0,0 +1,216 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use dav_server::{
+    davpath::DavPath,
+    fs::{
+        DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsResult, FsStream,
+        OpenOptions, ReadDirMeta,
+    },
+};
+use futures_util::future::{ready, FutureExt};
+use serde::Deserialize;
+use toml::Spanned;
+use tracing::info;
+
+use crate::config;
+use crate::model::Credentials;
+
+/// A PikPak account mounted under `/<name>/`
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub name: String,
+    pub credentials: Credentials,
+    /// Directory of the drive served under the mount
+    pub root: String,
+    pub proxy_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountsFile {
+    #[serde(default, rename = "account")]
+    accounts: Vec<AccountEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountEntry {
+    name: Spanned<String>,
+    username: String,
+    password: String,
+    /// The global `root` when left out
+    root: Option<String>,
+    /// The global `proxy_url` when left out
+    proxy_url: Option<String>,
+}
+
+/// Settings of an account that leaves them out, from the command line or config file.
+#[derive(Debug, Clone)]
+pub struct AccountDefaults {
+    pub root: String,
+    pub proxy_url: String,
+}
+
+/// Read the accounts of a TOML accounts file.
+pub async fn load(path: &Path, defaults: &AccountDefaults) -> Result<Vec<Account>> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("read accounts file {}", path.display()))?;
+    let accounts = parse(path, &content, defaults)?;
+    if accounts.is_empty() {
+        bail!("accounts file {}: no account", path.display());
+    }
+    Ok(accounts)
+}
+
+/// The `[[account]]` tables of an accounts or config file.
+pub fn parse(path: &Path, content: &str, defaults: &AccountDefaults) -> Result<Vec<Account>> {
+    let file: AccountsFile =
+        toml::from_str(content).with_context(|| format!("parse {}", path.display()))?;
+    let mut names = HashSet::new();
+    let mut accounts = Vec::with_capacity(file.accounts.len());
+    for entry in file.accounts {
+        let line = config::line_of(content, entry.name.start());
+        let name = entry.name.into_inner();
+        // names end up in urls as they are
+        let valid = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        if !valid || name.is_empty() || name.starts_with('.') {
+            bail!("{} line {}: invalid account name {:?}", path.display(), line, name);
+        }
+        if !names.insert(name.clone()) {
+            bail!("{} line {}: duplicate account {}", path.display(), line, name);
+        }
+        accounts.push(Account {
+            name,
+            credentials: Credentials {
+                username: entry.username,
+                password: entry.password,
+            },
+            root: entry.root.unwrap_or_else(|| defaults.root.clone()),
+            proxy_url: entry.proxy_url.unwrap_or_else(|| defaults.proxy_url.clone()),
+        });
+    }
+    if !accounts.is_empty() {
+        info!(path = %path.display(), accounts = accounts.len(), "accounts: loaded");
+    }
+    Ok(accounts)
+}
+
+/// Account name and the rest of `path` when it points into a mount.
+pub fn split_mount(path: &str) -> Option<(&str, &str)> {
+    let path = path.strip_prefix('/')?;
+    match path.find('/') {
+        Some(pos) => Some((&path[..pos], &path[pos..])),
+        None if !path.is_empty() => Some((path, "/")),
+        None => None,
+    }
+}
+
+/// The top level of a multi account server: one read-only folder per mount.
+#[derive(Debug, Clone)]
+pub struct MountsFs {
+    names: Vec<String>,
+    started: SystemTime,
+}
+
+impl MountsFs {
+    pub fn new(names: Vec<String>) -> Self {
+        Self {
+            names,
+            started: SystemTime::now(),
+        }
+    }
+
+    /// The listing as a user confined to the mount `name` sees it.
+    pub fn only(&self, name: &str) -> Self {
+        Self {
+            names: self.names.iter().filter(|n| *n == name).cloned().collect(),
+            started: self.started,
+        }
+    }
+
+    fn entry(&self, name: &str) -> MountEntry {
+        MountEntry {
+            name: name.to_string(),
+            modified: self.started,
+        }
+    }
+}
+
+impl DavFileSystem for MountsFs {
+    fn open<'a>(&'a self, _path: &'a DavPath, options: OpenOptions) -> FsFuture<Box<dyn DavFile>> {
+        let err = if options.write {
+            FsError::Forbidden
+        } else {
+            FsError::NotFound
+        };
+        ready(Err(err)).boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        _meta: ReadDirMeta,
+    ) -> FsFuture<FsStream<Box<dyn DavDirEntry>>> {
+        async move {
+            if path.as_rel_ospath() != Path::new("") {
+                return Err(FsError::NotFound);
+            }
+            let entries = self
+                .names
+                .iter()
+                .map(|name| Box::new(self.entry(name)) as Box<dyn DavDirEntry>)
+                .collect::<Vec<_>>();
+            Ok(Box::pin(futures_util::stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
+        async move {
+            if path.as_rel_ospath() != Path::new("") {
+                return Err(FsError::NotFound);
+            }
+            Ok(Box::new(self.entry("")) as Box<dyn DavMetaData>)
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, _path: &'a DavPath) -> FsFuture<()> {
+        // accounts come from the accounts file only
+        ready(Err(FsError::Forbidden)).boxed()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MountEntry {
+    name: String,
+    modified: SystemTime,
+}
+
+impl DavDirEntry for MountEntry {
+    fn name(&self) -> Vec<u8> {
+        self.name.as_bytes().to_vec()
+    }
+
+    fn metadata(&self) -> FsFuture<Box<dyn DavMetaData>> {
+        ready(Ok(Box::new(self.clone()) as Box<dyn DavMetaData>)).boxed()
+    }
+}
+
+impl DavMetaData for MountEntry {
+    fn len(&self) -> u64 {
+        0
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        Ok(self.modified)
+    }
+
+    fn is_dir(&self) -> bool {
+        true
+    }
+}
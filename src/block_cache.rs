@@ -0,0 +1,216 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::{debug, error, info, trace};
+
+#[derive(Debug, Clone)]
+pub struct BlockCacheConfig {
+    /// Directory the blocks are stored in
+    pub dir: PathBuf,
+    /// Size of a single cached block in bytes
+    pub block_size: u64,
+    /// Upper bound of all cached blocks in bytes
+    pub max_size: u64,
+}
+
+#[derive(Debug)]
+struct BlockEntry {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct BlockIndex {
+    entries: HashMap<(String, u64), BlockEntry>,
+    /// Keys by `last_used`, ticks are unique so the first is the least recently used
+    by_age: BTreeMap<u64, (String, u64)>,
+    total_size: u64,
+    tick: u64,
+}
+
+impl BlockIndex {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Mark a block used, false when it isn't cached.
+    fn touch(&mut self, key: &(String, u64)) -> bool {
+        let tick = self.next_tick();
+        let entry = match self.entries.get_mut(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let old = std::mem::replace(&mut entry.last_used, tick);
+        self.by_age.remove(&old);
+        self.by_age.insert(tick, key.clone());
+        true
+    }
+
+    fn insert(&mut self, key: (String, u64), size: u64) {
+        let last_used = self.next_tick();
+        self.remove(&key);
+        self.by_age.insert(last_used, key.clone());
+        self.entries.insert(key, BlockEntry { size, last_used });
+        self.total_size += size;
+    }
+
+    fn remove(&mut self, key: &(String, u64)) -> Option<BlockEntry> {
+        let entry = self.entries.remove(key)?;
+        self.by_age.remove(&entry.last_used);
+        self.total_size -= entry.size;
+        Some(entry)
+    }
+
+    fn pop_oldest(&mut self) -> Option<(String, u64)> {
+        let key = self.by_age.values().next()?.clone();
+        self.remove(&key);
+        Some(key)
+    }
+}
+
+/// Disk-backed cache of downloaded file content, split into fixed size blocks
+/// keyed by file id and block index and evicted least recently used first.
+#[derive(Clone)]
+pub struct BlockCache {
+    dir: PathBuf,
+    block_size: u64,
+    max_size: u64,
+    index: Arc<Mutex<BlockIndex>>,
+}
+
+impl BlockCache {
+    pub async fn new(config: BlockCacheConfig) -> Result<Self> {
+        tokio::fs::create_dir_all(&config.dir).await?;
+        let cache = Self {
+            dir: config.dir,
+            block_size: config.block_size,
+            max_size: config.max_size,
+            index: Arc::new(Mutex::new(BlockIndex::default())),
+        };
+        cache.load().await?;
+        Ok(cache)
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Bytes of content currently on disk.
+    pub fn size(&self) -> u64 {
+        self.index.lock().unwrap().total_size
+    }
+
+    /// Rebuild the index from blocks left behind by a previous run, oldest first.
+    async fn load(&self) -> Result<()> {
+        let mut blocks = Vec::new();
+        let mut file_dirs = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(file_dir) = file_dirs.next_entry().await? {
+            if !file_dir.file_type().await?.is_dir() {
+                continue;
+            }
+            let file_id = file_dir.file_name().to_string_lossy().into_owned();
+            let mut entries = tokio::fs::read_dir(file_dir.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let block = match name.parse::<u64>() {
+                    Ok(block) => block,
+                    Err(_) => {
+                        // a write that didn't finish
+                        if name.ends_with(".tmp") {
+                            let _ = tokio::fs::remove_file(entry.path()).await;
+                        }
+                        continue;
+                    }
+                };
+                let meta = entry.metadata().await?;
+                let modified = meta.modified().ok();
+                blocks.push((modified, file_id.clone(), block, meta.len()));
+            }
+        }
+        blocks.sort_by_key(|(modified, ..)| *modified);
+        let count = blocks.len();
+        {
+            let mut index = self.index.lock().unwrap();
+            for (_, file_id, block, size) in blocks {
+                index.insert((file_id, block), size);
+            }
+        }
+        info!(dir = %self.dir.display(), blocks = count, "block cache: loaded");
+        self.evict().await;
+        Ok(())
+    }
+
+    fn block_path(&self, file_id: &str, block: u64) -> PathBuf {
+        self.dir.join(file_id).join(block.to_string())
+    }
+
+    pub async fn get(&self, file_id: &str, block: u64) -> Option<Bytes> {
+        if !self.index.lock().unwrap().touch(&(file_id.to_string(), block)) {
+            return None;
+        }
+        match tokio::fs::read(self.block_path(file_id, block)).await {
+            Ok(content) => {
+                trace!(file_id = %file_id, block = block, "block cache: hit");
+                Some(Bytes::from(content))
+            }
+            Err(err) => {
+                debug!(file_id = %file_id, block = block, error = %err, "block cache: read block failed");
+                self.forget(file_id, block);
+                None
+            }
+        }
+    }
+
+    pub async fn put(&self, file_id: &str, block: u64, content: &Bytes) {
+        let path = self.block_path(file_id, block);
+        // readers never see a partial block, concurrent writers of it don't share a temp file
+        let seq = self.index.lock().unwrap().next_tick();
+        let tmp_path = path.with_file_name(format!("{}.{}.tmp", block, seq));
+        let res = match tokio::fs::create_dir_all(path.parent().unwrap()).await {
+            Ok(_) => match tokio::fs::write(&tmp_path, content).await {
+                Ok(_) => tokio::fs::rename(&tmp_path, &path).await,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            error!(path = %path.display(), error = %err, "block cache: write block failed");
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return;
+        }
+        self.index
+            .lock()
+            .unwrap()
+            .insert((file_id.to_string(), block), content.len() as u64);
+        self.evict().await;
+    }
+
+    fn forget(&self, file_id: &str, block: u64) {
+        self.index.lock().unwrap().remove(&(file_id.to_string(), block));
+    }
+
+    async fn evict(&self) {
+        loop {
+            let victim = {
+                let mut index = self.index.lock().unwrap();
+                if index.total_size <= self.max_size {
+                    break;
+                }
+                match index.pop_oldest() {
+                    Some(key) => key,
+                    None => break,
+                }
+            };
+            let (file_id, block) = victim;
+            trace!(file_id = %file_id, block = block, "block cache: evict");
+            let path = self.block_path(&file_id, block);
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                debug!(path = %path.display(), error = %err, "block cache: remove block failed");
+            }
+        }
+    }
+}
@@ -1,39 +1,148 @@
-use std::path::Path;
-use std::time::Duration;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use moka::future::{Cache as MokaCache, CacheBuilder};
-use tracing::{trace,debug};
+use serde::{Deserialize, Serialize};
+use tracing::{trace,debug,error,info};
 
+use crate::metrics;
 use crate::model::WebdavFile;
 
+/// Bump this whenever the on-disk layout or `WebdavFile` fields change,
+/// older files are discarded on load.
+const PERSIST_VERSION: u32 = 3;
+const PERSIST_FILE_NAME: &str = "meta_cache.json";
+const PERSIST_FLUSH_INTERVAL: u64 = 30;
+
+#[derive(Debug, Clone)]
+pub struct PersistConfig {
+    /// Directory the metadata file is written to, usually the workdir
+    pub dir: PathBuf,
+    /// Upper bound of the serialized entries in bytes
+    pub max_size: u64,
+}
+
 #[derive(Clone)]
 pub struct Cache {
-    inner: MokaCache<String, Vec<WebdavFile>>,
+    // swapped by `reconfigure`, together with its capacity and ttl
+    inner: Arc<RwLock<(MokaCache<String, Vec<WebdavFile>>, u64, u64)>>,
+    persist: Option<Arc<PersistentStore>>,
+}
+
+fn build(max_capacity: u64, ttl: u64) -> MokaCache<String, Vec<WebdavFile>> {
+    CacheBuilder::new(max_capacity)
+        .time_to_live(Duration::from_secs(ttl))
+        .build()
 }
 
 impl Cache {
     pub fn new(max_capacity: u64, ttl: u64) -> Self {
-        let inner = CacheBuilder::new(max_capacity)
-            .time_to_live(Duration::from_secs(ttl))
-            .build();
-        Self { inner }
+        let inner = build(max_capacity, ttl);
+        Self {
+            inner: Arc::new(RwLock::new((inner, max_capacity, ttl))),
+            persist: None,
+        }
+    }
+
+    /// Start over with an empty in-memory cache if the capacity or ttl changed.
+    pub fn reconfigure(&self, max_capacity: u64, ttl: u64) {
+        let mut inner = self.inner.write().unwrap();
+        if inner.1 == max_capacity && inner.2 == ttl {
+            return;
+        }
+        info!(max_capacity = max_capacity, ttl = ttl, "cache: reconfigured");
+        *inner = (build(max_capacity, ttl), max_capacity, ttl);
+    }
+
+    fn memory(&self) -> MokaCache<String, Vec<WebdavFile>> {
+        self.inner.read().unwrap().0.clone()
+    }
+
+    /// Directories listed in memory, approximately.
+    pub fn entry_count(&self) -> u64 {
+        self.memory().entry_count()
+    }
+
+    /// Bytes of listings in the on-disk store, if there is one.
+    pub fn persisted_size(&self) -> Option<u64> {
+        self.persist
+            .as_ref()
+            .map(|store| store.state.lock().unwrap().total_size)
+    }
+
+    /// Back this cache with an on-disk store, loading whatever a previous run left behind.
+    pub async fn persist_to(mut self, config: PersistConfig) -> Self {
+        let store = Arc::new(PersistentStore::load(config).await);
+        let flush_store = store.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(PERSIST_FLUSH_INTERVAL));
+            loop {
+                interval.tick().await;
+                if let Err(err) = flush_store.flush().await {
+                    error!(error = %err, "cache: flush metadata failed");
+                }
+            }
+        });
+        self.persist = Some(store);
+        self
     }
 
     #[allow(clippy::ptr_arg)]
     pub fn get(&self, key: &String) -> Option<Vec<WebdavFile>> {
         trace!(key = %key, "cache: get");
-        self.inner.get(key)
+        let value = self.memory().get(key);
+        metrics::cache_lookup(value.is_some());
+        value
+    }
+
+    /// Look up a listing in the on-disk store, the returned flag tells whether it
+    /// is older than the cache ttl and should be revalidated.
+    #[allow(clippy::ptr_arg)]
+    pub fn get_persisted(&self, key: &String) -> Option<(Vec<WebdavFile>, bool)> {
+        let store = self.persist.as_ref()?;
+        let (files, saved_at) = store.get(key)?;
+        trace!(key = %key, "cache: get persisted");
+        let ttl = self.inner.read().unwrap().2;
+        let stale = unix_now().saturating_sub(saved_at) >= ttl;
+        Some((files, stale))
+    }
+
+    /// Resolve a path to a file id through the persisted path index.
+    pub fn lookup_id(&self, path: &str) -> Option<String> {
+        self.persist.as_ref().and_then(|store| store.lookup_id(path))
+    }
+
+    /// Drop an id that no longer resolves, along with the ids below it.
+    pub fn forget_id(&self, path: &str) {
+        debug!(path = %path, "cache: forget id");
+        if let Some(store) = self.persist.as_ref() {
+            store.forget_id(path);
+        }
     }
 
     pub async fn insert(&self, key: String, value: Vec<WebdavFile>) {
         trace!(key = %key, "cache: insert");
-        self.inner.insert(key, value).await;
+        if let Some(store) = self.persist.as_ref() {
+            store.put(&key, &value);
+        }
+        self.memory().insert(key, value).await;
+    }
+
+    /// Keep a persisted listing in memory without touching its on-disk timestamp.
+    pub async fn promote(&self, key: String, value: Vec<WebdavFile>) {
+        trace!(key = %key, "cache: promote");
+        self.memory().insert(key, value).await;
     }
 
     pub async fn invalidate(&self, path: &Path) {
         let key = path.to_string_lossy().into_owned();
         debug!(path = %path.display(), key = %key, "cache: invalidate");
-        self.inner.invalidate(&key).await;
+        if let Some(store) = self.persist.as_ref() {
+            store.remove(&key);
+        }
+        self.memory().invalidate(&key).await;
     }
 
     pub async fn invalidate_parent(&self, path: &Path) {
@@ -42,9 +151,217 @@ impl Cache {
         }
     }
 
+    /// Write pending changes of the on-disk store now instead of at the next interval.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        match self.persist.as_ref() {
+            Some(store) => store.flush().await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PersistedData {
+    version: u32,
+    entries: HashMap<String, PersistedEntry>,
+    ids: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PersistedEntry {
+    saved_at: u64,
+    size: u64,
+    files: Vec<WebdavFile>,
+}
+
+struct PersistState {
+    data: PersistedData,
+    // keys ordered by `saved_at`, oldest first
+    by_age: BTreeSet<(u64, String)>,
+    total_size: u64,
+    dirty: bool,
+}
+
+/// Drop the ids of paths below `key`.
+fn prune_ids(ids: &mut BTreeMap<String, String>, key: &str) -> bool {
+    let prefix = format!("{}/", key.trim_end_matches('/'));
+    let below: Vec<String> = ids
+        .range(prefix.clone()..)
+        .take_while(|(path, _)| path.starts_with(&prefix))
+        .map(|(path, _)| path.clone())
+        .collect();
+    for path in &below {
+        ids.remove(path);
+    }
+    !below.is_empty()
+}
+
+struct PersistentStore {
+    path: PathBuf,
+    max_size: u64,
+    state: Mutex<PersistState>,
+}
+
+impl PersistentStore {
+    async fn load(config: PersistConfig) -> Self {
+        let path = config.dir.join(PERSIST_FILE_NAME);
+        let data = match tokio::fs::read(&path).await {
+            Ok(content) => match serde_json::from_slice::<PersistedData>(&content) {
+                Ok(data) if data.version == PERSIST_VERSION => data,
+                Ok(data) => {
+                    info!(version = data.version, "cache: discard metadata from another version");
+                    PersistedData::default()
+                }
+                Err(err) => {
+                    error!(path = %path.display(), error = %err, "cache: parse metadata failed");
+                    PersistedData::default()
+                }
+            },
+            Err(_) => PersistedData::default(),
+        };
+        let total_size = data.entries.values().map(|entry| entry.size).sum();
+        let by_age = data
+            .entries
+            .iter()
+            .map(|(key, entry)| (entry.saved_at, key.clone()))
+            .collect();
+        info!(path = %path.display(), entries = data.entries.len(), "cache: metadata loaded");
+        Self {
+            path,
+            max_size: config.max_size,
+            state: Mutex::new(PersistState {
+                data: PersistedData {
+                    version: PERSIST_VERSION,
+                    ..data
+                },
+                by_age,
+                total_size,
+                dirty: false,
+            }),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<(Vec<WebdavFile>, u64)> {
+        let state = self.state.lock().unwrap();
+        state
+            .data
+            .entries
+            .get(key)
+            .map(|entry| (entry.files.clone(), entry.saved_at))
+    }
+
+    fn lookup_id(&self, path: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.data.ids.get(path).cloned()
+    }
+
+    fn forget_id(&self, path: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.data.ids.remove(path).is_some() {
+            prune_ids(&mut state.data.ids, path);
+            state.dirty = true;
+        }
+    }
+
+    fn put(&self, key: &str, files: &[WebdavFile]) {
+        let size = serde_json::to_vec(files).map(|v| v.len() as u64).unwrap_or(0);
+        let mut state = self.state.lock().unwrap();
+        let dir = Path::new(key);
+        let child_path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        // children gone or replaced since the last listing take the ids below them along
+        let ids: HashMap<&str, &str> = files
+            .iter()
+            .map(|file| (file.name.as_str(), file.id.as_str()))
+            .collect();
+        let vanished: Vec<String> = match state.data.entries.get(key) {
+            Some(old) => old
+                .files
+                .iter()
+                .filter(|old| ids.get(old.name.as_str()) != Some(&old.id.as_str()))
+                .map(|old| child_path(&old.name))
+                .collect(),
+            None => Vec::new(),
+        };
+        for path in &vanished {
+            state.data.ids.remove(path);
+            prune_ids(&mut state.data.ids, path);
+        }
+        for file in files {
+            state.data.ids.insert(child_path(&file.name), file.id.clone());
+        }
+        let saved_at = unix_now();
+        let entry = PersistedEntry {
+            saved_at,
+            size,
+            files: files.to_vec(),
+        };
+        state.by_age.insert((saved_at, key.to_string()));
+        if let Some(old) = state.data.entries.insert(key.to_string(), entry) {
+            state.total_size -= old.size;
+            if old.saved_at != saved_at {
+                state.by_age.remove(&(old.saved_at, key.to_string()));
+            }
+        }
+        state.total_size += size;
+        state.dirty = true;
+        self.evict(&mut state);
+    }
 
-    pub fn invalidate_all(&self) {
-        trace!("cache: invalidate all");
-        self.inner.invalidate_all();
+    fn remove(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(old) = state.data.entries.remove(key) {
+            state.total_size -= old.size;
+            state.by_age.remove(&(old.saved_at, key.to_string()));
+            state.dirty = true;
+        }
+        if prune_ids(&mut state.data.ids, key) {
+            state.dirty = true;
+        }
     }
+
+    /// Drop the oldest listings until the store fits in `max_size`.
+    fn evict(&self, state: &mut PersistState) {
+        while state.total_size > self.max_size {
+            let oldest = match state.by_age.iter().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            state.by_age.remove(&oldest);
+            let (_, key) = oldest;
+            trace!(key = %key, "cache: evict persisted entry");
+            if let Some(old) = state.data.entries.remove(&key) {
+                state.total_size -= old.size;
+            }
+            prune_ids(&mut state.data.ids, &key);
+        }
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        let content = {
+            let mut state = self.state.lock().unwrap();
+            if !state.dirty {
+                return Ok(());
+            }
+            state.dirty = false;
+            serde_json::to_vec(&state.data)?
+        };
+        let tmp_path = self.path.with_extension("json.tmp");
+        let res = match tokio::fs::write(&tmp_path, &content).await {
+            Ok(_) => tokio::fs::rename(&tmp_path, &self.path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            self.state.lock().unwrap().dirty = true;
+            return Err(err.into());
+        }
+        debug!(path = %self.path.display(), size = content.len(), "cache: metadata flushed");
+        Ok(())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
 }
@@ -0,0 +1,122 @@
+use std::fmt;
+
+use crate::model::WebdavFile;
+
+const OC_NS: &str = "http://owncloud.org/ns";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha1,
+    Md5,
+    /// PikPak's content id, a SHA-1 over the SHA-1s of fixed size blocks
+    Gcid,
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "SHA1" | "SHA-1" => Some(Self::Sha1),
+            "MD5" => Some(Self::Md5),
+            "GCID" => Some(Self::Gcid),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Sha1 => "SHA1",
+            Self::Md5 => "MD5",
+            Self::Gcid => "GCID",
+        }
+    }
+}
+
+/// A content checksum in ownCloud's `ALGO:hex` notation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    pub value: String,
+}
+
+impl Checksum {
+    pub fn new(algorithm: Algorithm, value: &str) -> Self {
+        Self {
+            algorithm,
+            value: value.to_ascii_lowercase(),
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        let (algorithm, value) = s.trim().split_once(':')?;
+        let algorithm = Algorithm::parse(algorithm)?;
+        if value.is_empty() || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(Self::new(algorithm, value))
+    }
+
+    /// `OC-Checksum` headers and `checksums` props may carry several, separated by spaces.
+    pub fn parse_list(s: &str) -> Vec<Self> {
+        s.split(|c: char| c.is_whitespace() || c == ',')
+            .filter_map(Self::parse)
+            .collect()
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.value)
+    }
+}
+
+/// Checksums the drive knows for `file`, plus the SHA-1 declared when it was uploaded here.
+pub fn known(file: &WebdavFile, uploaded_sha1: Option<String>) -> Vec<Checksum> {
+    // files being uploaded carry a placeholder hash
+    if file.id.is_empty() || file.kind != "drive#file" {
+        return Vec::new();
+    }
+    let mut checksums = Vec::new();
+    if let Some(sha1) = uploaded_sha1 {
+        checksums.push(Checksum::new(Algorithm::Sha1, &sha1));
+    }
+    if !file.md5_checksum.is_empty() {
+        checksums.push(Checksum::new(Algorithm::Md5, &file.md5_checksum));
+    }
+    if let Some(gcid) = file.hash.as_deref().filter(|h| !h.is_empty()) {
+        checksums.push(Checksum::new(Algorithm::Gcid, gcid));
+    }
+    checksums
+}
+
+/// Whether some algorithm has a value in both lists and every such value agrees.
+pub fn matches(ours: &[Checksum], theirs: &[Checksum]) -> bool {
+    let mut compared = false;
+    for our in ours {
+        for their in theirs.iter().filter(|c| c.algorithm == our.algorithm) {
+            if their.value != our.value {
+                return false;
+            }
+            compared = true;
+        }
+    }
+    compared
+}
+
+/// The ownCloud `checksums` property, all values space separated in one element.
+pub fn checksums_xml(checksums: &[Checksum]) -> Vec<u8> {
+    let list = checksums
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        r#"<?xml version="1.0"?><oc:checksums xmlns:oc="{ns}"><oc:checksum>{list}</oc:checksum></oc:checksums>"#,
+        ns = OC_NS,
+        list = list,
+    )
+    .into_bytes()
+}
+
+pub fn is_checksums_prop(namespace: Option<&str>, name: &str) -> bool {
+    namespace == Some(OC_NS) && name == "checksums"
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use structopt::clap::ArgMatches;
+use toml::Spanned;
+
+use crate::access_log::AccessLogFormat;
+use crate::media::MediaPolicy;
+use crate::Opt;
+
+/// Settings of a `--config` file, named like the command line options with underscores.
+///
+/// `[[user]]` and `[[account]]` tables are read by the users and accounts modules.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    log_level: Option<String>,
+    host: Option<String>,
+    port: Option<u16>,
+    shutdown_timeout: Option<u64>,
+    auth_user: Option<Spanned<String>>,
+    auth_password: Option<Spanned<String>>,
+    read_only: Option<bool>,
+    users_file: Option<PathBuf>,
+    tls_cert: Option<Spanned<PathBuf>>,
+    tls_key: Option<Spanned<PathBuf>>,
+    tls_redirect_port: Option<Spanned<u16>>,
+    pikpak_user: Option<Spanned<String>>,
+    pikpak_password: Option<Spanned<String>>,
+    accounts_file: Option<PathBuf>,
+    proxy_url: Option<String>,
+    redirect: Option<bool>,
+    media_policy: Option<MediaPolicy>,
+    media_quality: Option<String>,
+    media_variants: Option<Vec<String>>,
+    hls: Option<bool>,
+    hls_direct: Option<bool>,
+    strm: Option<bool>,
+    link: Option<bool>,
+    link_secret: Option<String>,
+    link_ttl: Option<u64>,
+    metrics: Option<bool>,
+    public_status: Option<bool>,
+    access_log: Option<PathBuf>,
+    access_log_format: Option<AccessLogFormat>,
+    access_log_max_size: Option<u64>,
+    access_log_max_files: Option<usize>,
+    public_url: Option<String>,
+    read_buffer_size: Option<usize>,
+    upload_buffer_size: Option<usize>,
+    read_ahead_size: Option<usize>,
+    download_connections: Option<Spanned<usize>>,
+    download_chunk_size: Option<Spanned<usize>>,
+    cache_size: Option<u64>,
+    cache_ttl: Option<u64>,
+    root: Option<String>,
+    workdir: Option<PathBuf>,
+    meta_cache: Option<Spanned<bool>>,
+    meta_cache_size: Option<u64>,
+    block_cache: Option<Spanned<bool>>,
+    block_cache_size: Option<u64>,
+    block_cache_block_size: Option<Spanned<u64>>,
+    #[serde(rename = "user")]
+    _users: Option<toml::Value>,
+    #[serde(rename = "account")]
+    _accounts: Option<toml::Value>,
+}
+
+/// Lines of the config file options that are validated together with others.
+#[derive(Debug, Default)]
+pub struct ConfigLines {
+    path: Option<PathBuf>,
+    lines: HashMap<&'static str, usize>,
+}
+
+impl ConfigLines {
+    /// `<path> line <n>: ` for the first of `fields` set in the config file,
+    /// to put in front of an error message about them.
+    pub fn at(&self, fields: &[&str]) -> String {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return String::new(),
+        };
+        fields
+            .iter()
+            .find_map(|field| self.lines.get(field))
+            .map(|line| format!("{} line {}: ", path.display(), line))
+            .unwrap_or_default()
+    }
+}
+
+/// 1-based line of the byte `offset` in `content`, for error messages.
+pub fn line_of(content: &str, offset: usize) -> usize {
+    let offset = offset.min(content.len());
+    content.as_bytes()[..offset].iter().filter(|b| **b == b'\n').count() + 1
+}
+
+/// Whether the option was given on the command line or through its environment variable.
+fn overridden(matches: &ArgMatches, field: &str, env_var: Option<&str>) -> bool {
+    matches.occurrences_of(field.replace('_', "-")) > 0
+        || env_var.map_or(false, |var| env::var_os(var).is_some())
+}
+
+/// Fill every option of `opt` that wasn't given on the command line or
+/// through the environment from the config file at `path`.
+pub fn apply(opt: &mut Opt, matches: &ArgMatches, path: &Path, content: &str) -> Result<()> {
+    let file: ConfigFile =
+        toml::from_str(content).with_context(|| format!("parse config file {}", path.display()))?;
+
+    opt.config_lines.path = Some(path.to_path_buf());
+    macro_rules! merge {
+        ($($field:ident $(= $env:literal)?),* $(,)?) => {
+            $(
+                if let Some(value) = file.$field {
+                    let env_var: Option<&str> = None $(.or(Some($env)))?;
+                    if !overridden(matches, stringify!($field), env_var) {
+                        opt.$field = value.into();
+                    }
+                }
+            )*
+        };
+    }
+
+    macro_rules! merge_spanned {
+        ($($field:ident $(= $env:literal)?),* $(,)?) => {
+            $(
+                if let Some(value) = file.$field {
+                    let env_var: Option<&str> = None $(.or(Some($env)))?;
+                    if !overridden(matches, stringify!($field), env_var) {
+                        opt.config_lines
+                            .lines
+                            .insert(stringify!($field), line_of(content, value.start()));
+                        opt.$field = value.into_inner().into();
+                    }
+                }
+            )*
+        };
+    }
+
+    merge!(
+        log_level,
+        host = "HOST",
+        port = "PORT",
+        shutdown_timeout = "SHUTDOWN_TIMEOUT",
+        read_only,
+        users_file = "USERS_FILE",
+        accounts_file = "ACCOUNTS_FILE",
+        proxy_url = "PROXY_URL",
+        redirect = "REDIRECT",
+        media_policy,
+        media_quality,
+        media_variants,
+        hls,
+        hls_direct,
+        strm,
+        link,
+        link_secret = "LINK_SECRET",
+        link_ttl,
+        metrics,
+        public_status,
+        access_log = "ACCESS_LOG",
+        access_log_format,
+        access_log_max_size,
+        access_log_max_files,
+        public_url = "PUBLIC_URL",
+        read_buffer_size,
+        upload_buffer_size,
+        read_ahead_size,
+        cache_size,
+        cache_ttl,
+        root,
+        workdir,
+        meta_cache_size,
+        block_cache_size,
+    );
+    // checked against other options later, remember where they came from
+    merge_spanned!(
+        auth_user = "WEBDAV_AUTH_USER",
+        auth_password = "WEBDAV_AUTH_PASSWORD",
+        tls_cert = "TLS_CERT",
+        tls_key = "TLS_KEY",
+        tls_redirect_port = "TLS_REDIRECT_PORT",
+        pikpak_user = "PIKPAK_USER",
+        pikpak_password = "PIKPAK_PASSWORD",
+        download_connections,
+        download_chunk_size,
+        meta_cache,
+        block_cache,
+        block_cache_block_size,
+    );
+    Ok(())
+}
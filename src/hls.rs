@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dav_server::{body::Body, davpath::DavPath};
+use hyper::{Method, Request, Response, StatusCode};
+use moka::future::{Cache as MokaCache, CacheBuilder};
+use tracing::{debug, error};
+use url::{form_urlencoded, Url};
+
+use crate::vfs::WebdavDriveFileSystem;
+
+pub const HLS_PREFIX: &str = "/hls/";
+const PLAYLIST_SUFFIX: &str = ".m3u8";
+const SESSION_TTL: u64 = 60 * 60;
+/// Upstream urls a session proxies at most, later ones are handed out as is
+const SESSION_MAX_URLS: usize = 50_000;
+
+/// Upstream urls handed out as segments of one file, by index and by url.
+#[derive(Default)]
+struct Session {
+    urls: Vec<String>,
+    indexes: HashMap<String, usize>,
+}
+
+impl Session {
+    /// Index of `url`, registering it if there is room left.
+    fn index(&mut self, url: String) -> Option<usize> {
+        if let Some(index) = self.indexes.get(&url) {
+            return Some(*index);
+        }
+        if self.urls.len() >= SESSION_MAX_URLS {
+            return None;
+        }
+        let index = self.urls.len();
+        self.indexes.insert(url.clone(), index);
+        self.urls.push(url);
+        Some(index)
+    }
+}
+
+/// Serves `/hls/<path>.m3u8` playlists of PikPak video transcodes.
+///
+/// Segment and nested playlist urls are rewritten to `/hls/seg/<file_id>/<n>`
+/// and proxied through this server, or left pointing at the CDN in direct mode.
+#[derive(Clone)]
+pub struct HlsServer {
+    fs: WebdavDriveFileSystem,
+    direct: bool,
+    /// Mount prefix of the account in front of `/hls/`
+    prefix: String,
+    // root of the user's view and file id -> upstream urls handed out as
+    // segments, so segments only resolve for users who could open the playlist
+    sessions: MokaCache<(String, String), Arc<Mutex<Session>>>,
+}
+
+impl HlsServer {
+    pub fn new(fs: WebdavDriveFileSystem, direct: bool, prefix: String) -> Self {
+        let sessions = CacheBuilder::new(100)
+            .time_to_idle(Duration::from_secs(SESSION_TTL))
+            .build();
+        Self {
+            fs,
+            direct,
+            prefix,
+            sessions,
+        }
+    }
+
+    /// The same server resolving playlist paths in a user's view of the drive.
+    pub fn with_fs(&self, fs: WebdavDriveFileSystem) -> Self {
+        Self {
+            fs,
+            direct: self.direct,
+            prefix: self.prefix.clone(),
+            sessions: self.sessions.clone(),
+        }
+    }
+
+    /// Whether `path` is for this server rather than a drive folder named like it.
+    pub async fn matches(&self, path: &str) -> bool {
+        if !path.starts_with(HLS_PREFIX) {
+            return false;
+        }
+        let folder = DavPath::new(HLS_PREFIX.trim_end_matches('/')).unwrap();
+        !matches!(self.fs.lookup(&folder).await, Ok(Some(_)))
+    }
+
+    pub async fn handle(&self, req: &Request<hyper::Body>) -> Response<Body> {
+        match self.serve(req).await {
+            Ok(res) => res,
+            Err(status) => Response::builder()
+                .status(status)
+                .body(Body::from(status.to_string()))
+                .unwrap(),
+        }
+    }
+
+    async fn serve(&self, req: &Request<hyper::Body>) -> Result<Response<Body>, StatusCode> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Err(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let path = &req.uri().path()[HLS_PREFIX.len() - 1..];
+        debug!(path = %path, "hls: request");
+        if let Some(segment) = path.strip_prefix("/seg/") {
+            let (file_id, index) = segment.split_once('/').ok_or(StatusCode::NOT_FOUND)?;
+            let index = index.parse::<usize>().map_err(|_| StatusCode::NOT_FOUND)?;
+            return self.serve_segment(file_id, index).await;
+        }
+
+        let path = path.strip_suffix(PLAYLIST_SUFFIX).ok_or(StatusCode::NOT_FOUND)?;
+        let dav_path = DavPath::new(path).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let file = self
+            .fs
+            .lookup(&dav_path)
+            .await
+            .map_err(|_| StatusCode::NOT_FOUND)?
+            .ok_or(StatusCode::NOT_FOUND)?;
+        if file.kind != "drive#file" {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        let quality = req.uri().query().and_then(|query| {
+            form_urlencoded::parse(query.as_bytes())
+                .find(|(k, _)| k == "quality")
+                .map(|(_, v)| v.into_owned())
+        });
+        let url = self
+            .fs
+            .get_transcode_url(&file.id, quality.as_deref())
+            .await
+            .map_err(|err| {
+                error!(file_id = %file.id, error = %err, "hls: get transcode url failed");
+                StatusCode::NOT_FOUND
+            })?;
+        let res = self.fetch(&url).await?;
+        let body = res.text().await.map_err(|_| StatusCode::BAD_GATEWAY)?;
+        if !is_playlist(&body) {
+            return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+        Ok(self.playlist_response(&file.id, &url, &body).await)
+    }
+
+    async fn serve_segment(&self, file_id: &str, index: usize) -> Result<Response<Body>, StatusCode> {
+        let session = self
+            .sessions
+            .get(&self.session_key(file_id))
+            .ok_or(StatusCode::NOT_FOUND)?;
+        let url = session
+            .lock()
+            .unwrap()
+            .urls
+            .get(index)
+            .cloned()
+            .ok_or(StatusCode::NOT_FOUND)?;
+        let res = self.fetch(&url).await?;
+        let content_type = res
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let content = res.bytes().await.map_err(|_| StatusCode::BAD_GATEWAY)?;
+        if content_type.contains("mpegurl") || content.starts_with(b"#EXTM3U") {
+            let body = String::from_utf8_lossy(&content);
+            return Ok(self.playlist_response(file_id, &url, &body).await);
+        }
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", content_type)
+            .header("Content-Length", content.len())
+            .body(Body::from(content))
+            .unwrap())
+    }
+
+    async fn fetch(&self, url: &str) -> Result<reqwest::Response, StatusCode> {
+        self.fs.fetch(url).await.map_err(|err| {
+            error!(url = %url, error = %err, "hls: fetch upstream failed");
+            StatusCode::BAD_GATEWAY
+        })
+    }
+
+    async fn playlist_response(&self, file_id: &str, base_url: &str, body: &str) -> Response<Body> {
+        let session = if self.direct {
+            None
+        } else {
+            Some(self.session(file_id).await)
+        };
+        let playlist = rewrite(&self.prefix, file_id, session, base_url, body);
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/vnd.apple.mpegurl")
+            .header("Cache-Control", "no-cache")
+            .body(Body::from(playlist))
+            .unwrap()
+    }
+
+    fn session_key(&self, file_id: &str) -> (String, String) {
+        (self.fs.root().to_string_lossy().into_owned(), file_id.to_string())
+    }
+
+    async fn session(&self, file_id: &str) -> Arc<Mutex<Session>> {
+        let key = self.session_key(file_id);
+        if let Some(session) = self.sessions.get(&key) {
+            return session;
+        }
+        let session = Arc::new(Mutex::new(Session::default()));
+        self.sessions.insert(key, session.clone()).await;
+        session
+    }
+}
+
+/// Rewrite segment and nested playlist urls, registering them in `session` when proxying.
+fn rewrite(prefix: &str, file_id: &str, session: Option<Arc<Mutex<Session>>>, base_url: &str, body: &str) -> String {
+    let base = Url::parse(base_url).ok();
+    let rewrite_uri = |uri: &str| -> String {
+        let target = match base.as_ref().and_then(|base| base.join(uri).ok()) {
+            Some(target) => target.to_string(),
+            None => uri.to_string(),
+        };
+        let index = session
+            .as_ref()
+            .and_then(|session| session.lock().unwrap().index(target.clone()));
+        match index {
+            Some(index) => format!("{}{}seg/{}/{}", prefix, HLS_PREFIX, file_id, index),
+            None => target,
+        }
+    };
+
+    let mut out = String::with_capacity(body.len());
+    for line in body.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            // keep blank lines
+        } else if trimmed.starts_with('#') {
+            // tags such as EXT-X-KEY and EXT-X-MEDIA carry URI attributes
+            match trimmed.find("URI=\"") {
+                Some(start) => {
+                    let start = start + "URI=\"".len();
+                    let end = trimmed[start..].find('"').map(|i| start + i).unwrap_or(trimmed.len());
+                    out.push_str(&trimmed[..start]);
+                    out.push_str(&rewrite_uri(&trimmed[start..end]));
+                    out.push_str(&trimmed[end..]);
+                }
+                None => out.push_str(trimmed),
+            }
+        } else {
+            out.push_str(&rewrite_uri(trimmed));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn is_playlist(body: &str) -> bool {
+    body.trim_start().starts_with("#EXTM3U")
+}
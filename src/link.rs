@@ -0,0 +1,267 @@
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use dav_server::body::Body;
+use hmacsha::HmacSha;
+use hyper::{Method, Request, Response, StatusCode};
+use moka::future::{Cache as MokaCache, CacheBuilder};
+use sha1::Sha1;
+use time::{format_description, PrimitiveDateTime};
+use tracing::{debug, error, info, trace, warn};
+use url::{form_urlencoded, Url};
+
+use crate::vfs::WebdavDriveFileSystem;
+
+pub const LINK_PREFIX: &str = "/link/";
+/// Query parameters CDNs use to carry an absolute unix expiry time
+const EXPIRY_PARAMS: &[&str] = &["x-oss-expires", "expires", "expire", "e"];
+/// Cached links are refreshed once they get this close to expiry
+const LINK_REFRESH_MARGIN: u64 = 5 * 60;
+/// Lifetime assumed for links that don't tell their expiry
+const LINK_DEFAULT_TTL: u64 = 10 * 60;
+/// Generated signing key, kept in the workdir
+const LINK_SECRET_FILE_NAME: &str = "link_secret";
+
+/// Unix time a signed download url stops working, if the url says so.
+pub fn url_expires_at(url: &str) -> Option<u64> {
+    let url = Url::parse(url).ok()?;
+    let mut amz_date = None;
+    let mut amz_expires = None;
+    for (k, v) in url.query_pairs() {
+        if EXPIRY_PARAMS.iter().any(|p| k.eq_ignore_ascii_case(p)) {
+            // small values are relative lifetimes, not timestamps
+            if let Ok(expires) = v.parse::<u64>() {
+                if expires > 1_000_000_000 {
+                    return Some(expires);
+                }
+            }
+        } else if k.eq_ignore_ascii_case("x-amz-date") {
+            let format = format_description::parse("[year][month][day]T[hour][minute][second]Z").ok()?;
+            amz_date = PrimitiveDateTime::parse(&v, &format)
+                .ok()
+                .map(|dt| dt.assume_utc().unix_timestamp() as u64);
+        } else if k.eq_ignore_ascii_case("x-amz-expires") {
+            amz_expires = v.parse::<u64>().ok();
+        }
+    }
+    match (amz_date, amz_expires) {
+        (Some(date), Some(expires)) => Some(date + expires),
+        _ => None,
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Download urls by file id, handed out again until shortly before they expire.
+#[derive(Clone)]
+pub struct LinkCache {
+    inner: MokaCache<String, (String, u64)>,
+}
+
+impl LinkCache {
+    pub fn new(max_capacity: u64) -> Self {
+        let inner = CacheBuilder::new(max_capacity)
+            .time_to_live(Duration::from_secs(24 * 60 * 60))
+            .build();
+        Self { inner }
+    }
+
+    #[allow(clippy::ptr_arg)]
+    pub fn get(&self, key: &String) -> Option<String> {
+        let (url, expires_at) = self.inner.get(key)?;
+        if unix_now() + LINK_REFRESH_MARGIN >= expires_at {
+            trace!(key = %key, "link cache: expiring");
+            return None;
+        }
+        trace!(key = %key, "link cache: hit");
+        Some(url)
+    }
+
+    pub async fn insert(&self, key: String, url: String) {
+        let expires_at = url_expires_at(&url).unwrap_or_else(|| unix_now() + LINK_DEFAULT_TTL);
+        trace!(key = %key, expires_at = expires_at, "link cache: insert");
+        self.inner.insert(key, (url, expires_at)).await;
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Key for signing playback links, generated on first use and kept in the
+/// workdir. Without a workdir it lasts as long as the process, and so do the links.
+pub async fn load_secret(workdir: Option<&Path>) -> Result<String> {
+    let path = workdir.map(|dir| dir.join(LINK_SECRET_FILE_NAME));
+    if let Some(path) = path.as_ref() {
+        match tokio::fs::read_to_string(path).await {
+            Ok(secret) if !secret.trim().is_empty() => return Ok(secret.trim().to_string()),
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).with_context(|| format!("read link secret {}", path.display())),
+        }
+    }
+    let mut key = [0u8; 32];
+    getrandom::getrandom(&mut key).map_err(|err| anyhow!("generate link secret: {}", err))?;
+    let secret = hex(&key);
+    match path {
+        Some(path) => {
+            let mut options = tokio::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut file = options
+                .open(&path)
+                .await
+                .with_context(|| format!("write link secret {}", path.display()))?;
+            tokio::io::AsyncWriteExt::write_all(&mut file, secret.as_bytes()).await?;
+            info!(path = %path.display(), "link: generated signing key");
+        }
+        None => warn!("workdir not specified, signed links stop working on restart"),
+    }
+    Ok(secret)
+}
+
+/// Compare without stopping at the first difference, so the time taken
+/// doesn't tell how much of a forged signature was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Signs playback links so players can use them without WebDAV credentials.
+/// Clones share the secret, so a reload reaches every server holding one.
+#[derive(Debug, Clone)]
+pub struct LinkSigner {
+    secret: Arc<RwLock<Option<String>>>,
+    /// Seconds a signed link stays valid, 0 for links that never expire
+    ttl: u64,
+}
+
+impl LinkSigner {
+    /// Links are only signed when a secret is set, i.e. when the server requires auth.
+    pub fn new(secret: Option<String>, ttl: u64) -> Self {
+        Self {
+            secret: Arc::new(RwLock::new(secret)),
+            ttl,
+        }
+    }
+
+    /// Sign with the secret of a reloaded configuration from now on,
+    /// links signed with the previous one stop working.
+    pub fn set_secret(&self, secret: Option<String>) {
+        *self.secret.write().unwrap() = secret;
+    }
+
+    fn digest(secret: &str, file_id: &str, expires: Option<u64>) -> String {
+        let payload = match expires {
+            Some(expires) => format!("{}\n{}", file_id, expires),
+            None => file_id.to_string(),
+        };
+        let mut hasher = HmacSha::from(secret, &payload, Sha1::default());
+        hex(&hasher.compute_digest())
+    }
+
+    /// Query string authorizing `/link/<file_id>`, none when links aren't signed.
+    pub fn query(&self, file_id: &str) -> Option<String> {
+        let secret = self.secret.read().unwrap().clone()?;
+        if self.ttl == 0 {
+            return Some(format!("sign={}", Self::digest(&secret, file_id, None)));
+        }
+        let expires = unix_now() + self.ttl;
+        let sign = Self::digest(&secret, file_id, Some(expires));
+        Some(format!("expires={}&sign={}", expires, sign))
+    }
+
+    fn verify(&self, file_id: &str, sign: Option<&str>, expires: Option<&str>) -> bool {
+        let secret = match self.secret.read().unwrap().clone() {
+            Some(secret) => secret,
+            // no users, nothing to sign with
+            None => return true,
+        };
+        let sign = match sign {
+            Some(sign) => sign.to_ascii_lowercase(),
+            None => return false,
+        };
+        let expires = match expires {
+            Some(expires) => match expires.parse::<u64>() {
+                Ok(expires) if expires > unix_now() => Some(expires),
+                _ => return false,
+            },
+            None if self.ttl > 0 => return false,
+            None => None,
+        };
+        let expected = Self::digest(&secret, file_id, expires);
+        constant_time_eq(expected.as_bytes(), sign.as_bytes())
+    }
+}
+
+/// Serves `/link/<file_id>` by redirecting to a freshly resolved download url.
+#[derive(Clone)]
+pub struct LinkServer {
+    fs: WebdavDriveFileSystem,
+    signer: LinkSigner,
+}
+
+impl LinkServer {
+    pub fn new(fs: WebdavDriveFileSystem, signer: LinkSigner) -> Self {
+        Self { fs, signer }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        path.starts_with(LINK_PREFIX)
+    }
+
+    /// A valid `sign` query parameter is required whether or not the request
+    /// passed WebDAV auth, the file id alone would reach outside a user's root.
+    pub async fn handle(&self, req: &Request<hyper::Body>) -> Response<Body> {
+        match self.serve(req).await {
+            Ok(res) => res,
+            Err(status) => Response::builder()
+                .status(status)
+                .body(Body::from(status.to_string()))
+                .unwrap(),
+        }
+    }
+
+    async fn serve(&self, req: &Request<hyper::Body>) -> Result<Response<Body>, StatusCode> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Err(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let file_id = req.uri().path()[LINK_PREFIX.len()..].trim_end_matches('/');
+        if file_id.is_empty() || file_id.contains('/') {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        let mut sign = None;
+        let mut expires = None;
+        if let Some(query) = req.uri().query() {
+            for (k, v) in form_urlencoded::parse(query.as_bytes()) {
+                match k.as_ref() {
+                    "sign" => sign = Some(v.into_owned()),
+                    "expires" => expires = Some(v.into_owned()),
+                    _ => {}
+                }
+            }
+        }
+        if !self.signer.verify(file_id, sign.as_deref(), expires.as_deref()) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        debug!(file_id = %file_id, "link: resolve");
+        let url = self.fs.get_download_url(file_id, None).await.map_err(|err| {
+            error!(file_id = %file_id, error = %err, "link: get download url failed");
+            StatusCode::NOT_FOUND
+        })?;
+        Ok(Response::builder()
+            .status(StatusCode::FOUND)
+            .header("Location", url)
+            .header("Cache-Control", "no-store")
+            .body(Body::empty())
+            .unwrap())
+    }
+}
@@ -1,46 +1,183 @@
 use std::convert::Infallible;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use std::{env, io, path::PathBuf};
 
 use headers::{authorization::Basic, Authorization, HeaderMapExt};
-use structopt::StructOpt;
-use tracing::{debug, error, info};
+use hyper::server::conn::AddrStream;
+use anyhow::Context;
+use structopt::{clap::ArgMatches, StructOpt};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_subscriber::{prelude::*, reload, EnvFilter, Registry};
+use tracing::{debug, error, info, warn};
 //use webdav_handler::{body::Body, memls::MemLs, fakels::FakeLs, DavConfig, DavHandler};
-use dav_server::{body::Body, memls::MemLs,DavConfig, DavHandler};
+use dav_server::{body::Body, davpath::DavPath, fs::DavMetaData, memls::MemLs,DavConfig, DavHandler};
 use vfs::WebdavDriveFileSystem;
 use model::Credentials;
+use cache::PersistConfig;
+use block_cache::{BlockCache, BlockCacheConfig};
+use media::{MediaConfig, MediaPolicy};
+use hls::HlsServer;
+use link::{LinkServer, LinkSigner};
+use strm::StrmConfig;
+use thumbnail::ThumbnailServer;
+use sidecar::SidecarStore;
+use tls::TlsConfig;
+use status::StatusServer;
+use access_log::{AccessLog, AccessLogConfig, AccessLogFormat, LoggedBody};
+use users::{Permissions, Users};
+use accounts::{Account, AccountDefaults, MountsFs};
 
 mod vfs;
 mod model;
 mod cache;
+mod block_cache;
+mod reader;
+mod media;
+mod hls;
+mod link;
+mod strm;
+mod props;
+mod thumbnail;
+mod sidecar;
+mod checksum;
+mod tls;
+mod users;
+mod accounts;
+mod config;
+mod metrics;
+mod status;
+mod access_log;
 
+/// Log filter used when neither --log-level nor RUST_LOG is set
+const DEFAULT_LOG_FILTER: &str = "pikpak_webdav=info,reqwest=warn";
+/// PROPPATCH bodies are read into memory to move DAV: modification times, up to this size
+const MAX_PROPPATCH_BODY: usize = 64 * 1024;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "webdav")]
 struct Opt {
+    /// TOML config file, options given on the command line or in the environment take precedence
+    #[structopt(short = "c", long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    /// Log filter such as info or pikpak_webdav=debug, overrides RUST_LOG
+    #[structopt(long)]
+    log_level: Option<String>,
     /// Listen host
     #[structopt(long, env = "HOST", default_value = "0.0.0.0")]
     host: String,
     /// Listen port
     #[structopt(short, env = "PORT", long, default_value = "9867")]
     port: u16,
+    /// Seconds active transfers get to finish on SIGTERM before unfinished uploads are aborted
+    #[structopt(long, env = "SHUTDOWN_TIMEOUT", default_value = "30")]
+    shutdown_timeout: u64,
     /// WebDAV authentication username
     #[structopt(short = "U", long, env = "WEBDAV_AUTH_USER")]
     auth_user: Option<String>,
     /// WebDAV authentication password
     #[structopt(short = "W", long, env = "WEBDAV_AUTH_PASSWORD")]
     auth_password: Option<String>,
+    /// Reject every change to the drive, for all users
+    #[structopt(long)]
+    read_only: bool,
+    /// TOML file of WebDAV users with bcrypt password hashes, roots and permissions
+    #[structopt(long, env = "USERS_FILE")]
+    users_file: Option<PathBuf>,
+    /// TLS certificate chain in PEM format, serves HTTPS together with --tls-key
+    #[structopt(long, env = "TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// TLS private key in PEM format
+    #[structopt(long, env = "TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// Also listen for plain HTTP on this port and redirect it to HTTPS
+    #[structopt(long, env = "TLS_REDIRECT_PORT")]
+    tls_redirect_port: Option<u16>,
 
 
     #[structopt(long, env = "PIKPAK_USER")]
-    pikpak_user: String,
+    pikpak_user: Option<String>,
 
     #[structopt(long, env = "PIKPAK_PASSWORD")]
-    pikpak_password: String,
+    pikpak_password: Option<String>,
+
+    /// TOML file of PikPak accounts, each served under /<name>/ instead of --pikpak-user
+    #[structopt(long, env = "ACCOUNTS_FILE")]
+    accounts_file: Option<PathBuf>,
 
     #[structopt(long, env = "PROXY_URL", default_value = "")]
     proxy_url: String,
 
+    /// Answer GET on files with a 302 redirect to the PikPak download url
+    #[structopt(long, env = "REDIRECT")]
+    redirect: bool,
+
+    /// Stream served for videos: original or transcoded
+    #[structopt(long, default_value = "transcoded", possible_values = &["original", "transcoded"])]
+    media_policy: MediaPolicy,
+
+    /// Preferred transcode quality label, e.g. 720P
+    #[structopt(long)]
+    media_quality: Option<String>,
+
+    /// Transcode labels listed as virtual `name [label].mp4` siblings of videos, comma separated
+    #[structopt(long, use_delimiter = true)]
+    media_variants: Vec<String>,
+
+    /// Serve HLS playlists of video transcodes under /hls/
+    #[structopt(long)]
+    hls: bool,
+
+    /// Let HLS players fetch segments straight from the CDN instead of through this server
+    #[structopt(long)]
+    hls_direct: bool,
+
+    /// Mirror the drive under /.strm/ with videos as .strm files for media servers
+    #[structopt(long)]
+    strm: bool,
+
+    /// Serve /link/<file_id> redirects to always fresh download urls
+    #[structopt(long)]
+    link: bool,
+
+    /// Key signing /link urls, defaults to one generated and kept in the workdir. Changing it invalidates handed out links
+    #[structopt(long, env = "LINK_SECRET")]
+    link_secret: Option<String>,
+
+    /// Seconds signed /link urls stay valid, 0 never expires them. Media servers may keep .strm contents for long
+    #[structopt(long, default_value = "0")]
+    link_ttl: u64,
+
+    /// Serve Prometheus metrics under /metrics to authenticated users
+    #[structopt(long)]
+    metrics: bool,
+
+    /// Answer /status without WebDAV credentials, /healthz never needs them
+    #[structopt(long)]
+    public_status: bool,
+
+    /// Append a line per request with method, path, status, bytes, duration, user and client to this file
+    #[structopt(long, env = "ACCESS_LOG")]
+    access_log: Option<PathBuf>,
+
+    /// Access log line format: combined or json
+    #[structopt(long, default_value = "combined", possible_values = &["combined", "json"])]
+    access_log_format: AccessLogFormat,
+
+    /// Rotate the access log once it grows past this many bytes, defaults to 10MB, 0 never rotates
+    #[structopt(long, default_value = "10485760")]
+    access_log_max_size: u64,
+
+    /// Rotated access logs to keep
+    #[structopt(long, default_value = "5")]
+    access_log_max_files: usize,
+
+    /// Externally reachable url of this server used in .strm files, e.g. http://192.168.1.2:9867
+    #[structopt(long, env = "PUBLIC_URL")]
+    public_url: Option<String>,
+
     /// Automatically generate index.html
     // #[structopt(short = "I", long)]
     // auto_index: bool,
@@ -51,6 +188,18 @@ struct Opt {
     #[structopt(long, default_value = "16777216")]
     upload_buffer_size: usize,
 
+    /// Bytes to prefetch ahead of sequential reads, 0 issues one request per read, defaults to 20MB
+    #[structopt(long, default_value = "20971520")]
+    read_ahead_size: usize,
+
+    /// Concurrent connections used for large reads, 1 disables parallel downloads
+    #[structopt(long, default_value = "1")]
+    download_connections: usize,
+
+    /// Size of each ranged request in a parallel download, defaults to 4MB
+    #[structopt(long, default_value = "4194304")]
+    download_chunk_size: usize,
+
     /// Directory entries cache size
     #[structopt(long, default_value = "1000")]
     cache_size: u64,
@@ -63,6 +212,24 @@ struct Opt {
     /// Working directory, refresh_token will be stored in there if specified
     #[structopt(short = "w", long)]
     workdir: Option<PathBuf>,
+    /// Persist directory listings to the working directory across restarts
+    #[structopt(long)]
+    meta_cache: bool,
+    /// Max size of the persisted directory listings in bytes, defaults to 64MB
+    #[structopt(long, default_value = "67108864")]
+    meta_cache_size: u64,
+    /// Cache downloaded content in the working directory
+    #[structopt(long)]
+    block_cache: bool,
+    /// Max size of the downloaded content cache in bytes, defaults to 1GB
+    #[structopt(long, default_value = "1073741824")]
+    block_cache_size: u64,
+    /// Downloaded content cache block size in bytes, defaults to 4MB
+    #[structopt(long, default_value = "4194304")]
+    block_cache_block_size: u64,
+    /// Where the options from the config file were set, for error messages
+    #[structopt(skip)]
+    config_lines: config::ConfigLines,
 
 }
 
@@ -71,39 +238,175 @@ async fn main() -> anyhow::Result<()> {
     #[cfg(feature = "native-tls-vendored")]
     openssl_probe::init_ssl_cert_env_vars();
 
-    let opt = Opt::from_args();
-    let auth_user = opt.auth_user;
-    let auth_pwd = opt.auth_password;
+    let matches = Opt::clap().get_matches();
+    let (opt, config) = load_options(&matches).await?;
 
-    tracing_subscriber::fmt::init();
-    if env::var("RUST_LOG").is_err() {
-       env::set_var("RUST_LOG", "pikpak_webdav=info,reqwest=warn");
+    let (log_filter, log_handle) = reload::Layer::new(log_filter(&opt)?);
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    if opt.tls_cert.is_some() != opt.tls_key.is_some() {
+        anyhow::bail!(
+            "{}tls-cert and tls-key should be specified together.",
+            opt.config_lines.at(&["tls_cert", "tls_key"])
+        );
+    }
+    if opt.tls_redirect_port.is_some() && opt.tls_cert.is_none() {
+        anyhow::bail!(
+            "{}tls-redirect-port requires tls-cert and tls-key to be specified.",
+            opt.config_lines.at(&["tls_redirect_port"])
+        );
     }
 
+    let (accounts, multi_account) = load_accounts(&opt, config.as_ref()).await?;
+    let users = load_users(&opt, config.as_ref()).await?;
 
-    if (auth_user.is_some() && auth_pwd.is_none()) || (auth_user.is_none() && auth_pwd.is_some()) {
-        anyhow::bail!("auth-user and auth-password should be specified together.");
+    if opt.meta_cache && opt.workdir.is_none() {
+        anyhow::bail!(
+            "{}meta-cache requires workdir to be specified.",
+            opt.config_lines.at(&["meta_cache"])
+        );
     }
 
-    let credentials = Credentials{
-        username:opt.pikpak_user,
-        password:opt.pikpak_password,
+    if opt.download_connections == 0 || opt.download_chunk_size == 0 {
+        anyhow::bail!(
+            "{}download-connections and download-chunk-size should be greater than 0.",
+            opt.config_lines.at(&["download_connections", "download_chunk_size"])
+        );
+    }
+
+    let generated_link_secret = if opt.link || opt.strm {
+        link::load_secret(opt.workdir.as_deref()).await?
+    } else {
+        String::new()
+    };
+    let link_signer = LinkSigner::new(link_secret(&opt, &users, &generated_link_secret), opt.link_ttl);
+
+    let media = MediaConfig {
+        policy: opt.media_policy,
+        quality: opt.media_quality,
+        variants: opt.media_variants,
     };
 
-    let fs = WebdavDriveFileSystem::new(credentials,opt.root, opt.cache_size, opt.cache_ttl,opt.proxy_url,opt.upload_buffer_size,false,false)
-        .await
-        .map_err(|_| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                "initialize WebdavDriveFileSystem file system failed",
-            )
-        })?;
-    info!("WebdavDriveFileSystem file system initialized");
+    let public_url = if opt.strm {
+        match opt.public_url.as_ref() {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => {
+                let url = format!("http://127.0.0.1:{}", opt.port);
+                warn!("public-url not specified, .strm files will point to {}", url);
+                url
+            }
+        }
+    } else {
+        String::new()
+    };
+
+    if opt.block_cache {
+        if opt.block_cache_block_size == 0 {
+            anyhow::bail!(
+                "{}block-cache-block-size should be greater than 0.",
+                opt.config_lines.at(&["block_cache_block_size"])
+            );
+        }
+        if opt.workdir.is_none() {
+            anyhow::bail!(
+                "{}block-cache requires workdir to be specified.",
+                opt.config_lines.at(&["block_cache"])
+            );
+        }
+    }
+
+    let mut mounts = Vec::with_capacity(accounts.len());
+    for account in accounts {
+        // every account keeps its caches and sidecar store in a workdir of its own
+        let prefix = if multi_account {
+            format!("/{}", account.name)
+        } else {
+            String::new()
+        };
+        let workdir = opt.workdir.as_ref().map(|dir| {
+            if multi_account {
+                dir.join(&account.name)
+            } else {
+                dir.clone()
+            }
+        });
+        if let Some(dir) = workdir.as_ref() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+
+        let meta_cache = match workdir.as_ref() {
+            Some(dir) if opt.meta_cache => Some(PersistConfig {
+                dir: dir.clone(),
+                max_size: opt.meta_cache_size,
+            }),
+            _ => None,
+        };
+        let block_cache = match workdir.as_ref() {
+            Some(dir) if opt.block_cache => {
+                let config = BlockCacheConfig {
+                    dir: dir.join("blocks"),
+                    block_size: opt.block_cache_block_size,
+                    max_size: opt.block_cache_size,
+                };
+                Some(BlockCache::new(config).await?)
+            }
+            _ => None,
+        };
+        let strm = if opt.strm {
+            Some(StrmConfig {
+                base_url: format!("{}{}", public_url, prefix),
+                signer: link_signer.clone(),
+            })
+        } else {
+            None
+        };
+        let sidecar = SidecarStore::load(workdir.as_deref()).await;
+
+        let fs = WebdavDriveFileSystem::new(account.credentials,account.root, opt.cache_size, opt.cache_ttl,account.proxy_url,opt.upload_buffer_size,false,false,meta_cache,block_cache,opt.read_ahead_size,opt.download_connections,opt.download_chunk_size,media.clone(),strm,sidecar)
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    "initialize WebdavDriveFileSystem file system failed",
+                )
+            })?;
+        info!(account = %account.name, "WebdavDriveFileSystem file system initialized");
+        let fs = if opt.read_only {
+            fs.for_user("/", Permissions::ReadOnly)
+        } else {
+            fs
+        };
+        let link = if opt.link || opt.strm {
+            Some(LinkServer::new(fs.clone(), link_signer.clone()))
+        } else {
+            None
+        };
+        let hls = if opt.hls {
+            Some(HlsServer::new(fs.clone(), opt.hls_direct, prefix.clone()))
+        } else {
+            None
+        };
+        mounts.push(Mount {
+            name: account.name,
+            prefix,
+            fs,
+            hls,
+            link,
+        });
+    }
+    let top = if multi_account {
+        Some(MountsFs::new(mounts.iter().map(|m| m.name.clone()).collect()))
+    } else {
+        None
+    };
     let dav_server = DavHandler::builder()
-        .filesystem(Box::new(fs))
         .locksystem(MemLs::new())
         .read_buf_size(opt.read_buffer_size)
         .autoindex(true)
+        .redirect(opt.redirect)
         .build_handler();
 
     ////本地文件////
@@ -117,6 +420,7 @@ async fn main() -> anyhow::Result<()> {
     debug!(
         read_buffer_size = opt.read_buffer_size,
         auto_index = true,
+        redirect = opt.redirect,
         "webdav handler initialized"
     );
 
@@ -127,54 +431,665 @@ async fn main() -> anyhow::Result<()> {
         .unwrap();
     info!("listening on {:?}", addr);
 
-    let make_service = hyper::service::make_service_fn(move |_| {
-        let auth_user = auth_user.clone();
-        let auth_pwd = auth_pwd.clone();
-        let should_auth = auth_user.is_some() && auth_pwd.is_some();
-        let dav_server = dav_server.clone();
+    let access_log = match opt.access_log.clone() {
+        Some(path) => Some(
+            AccessLog::open(AccessLogConfig {
+                path,
+                format: opt.access_log_format,
+                max_size: opt.access_log_max_size,
+                max_files: opt.access_log_max_files,
+            })
+            .await?,
+        ),
+        None => None,
+    };
+
+    let app = App {
+        users: Arc::new(RwLock::new(if users.is_empty() { None } else { Some(users) })),
+        dav_server,
+        mounts: Arc::new(mounts),
+        top,
+        metrics: opt.metrics,
+        status: StatusServer::new(opt.public_status),
+        access_log,
+        link_signer,
+    };
+
+    #[cfg(unix)]
+    {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(err) => {
+                    error!(error = %err, "listen for SIGHUP failed");
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                info!("SIGHUP received, reloading configuration");
+                match reload_config(&matches, &app, &log_handle, &generated_link_secret).await {
+                    Ok(_) => info!("configuration reloaded"),
+                    // keep running with what we have
+                    Err(err) => error!(error = %err, "reload configuration failed"),
+                }
+            }
+        });
+    }
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        wait_for_shutdown().await;
+        info!("shutting down, waiting for active transfers");
+        let _ = shutdown_tx.send(true);
+    });
+    let mounts = app.mounts.clone();
+    let shutdown_timeout = opt.shutdown_timeout;
+
+    let server = {
+        let shutdown_rx = shutdown_rx.clone();
         async move {
-            let func = move |req: hyper::Request<hyper::Body>| {
-                let dav_server = dav_server.clone();
-                let auth_user = auth_user.clone();
-                let auth_pwd = auth_pwd.clone();
-                async move {
-                    if should_auth {
-                        let auth_user = auth_user.unwrap();
-                        let auth_pwd = auth_pwd.unwrap();
-                        let user = match req.headers().typed_get::<Authorization<Basic>>() {
-                            Some(Authorization(basic))
-                                if basic.username() == auth_user
-                                    && basic.password() == auth_pwd =>
-                            {
-                                basic.username().to_string()
+            match (opt.tls_cert, opt.tls_key) {
+                (Some(cert), Some(key)) => {
+                    let acceptor = tls::acceptor(TlsConfig { cert, key })?;
+                    if let Some(http_port) = opt.tls_redirect_port {
+                        let http_addr = SocketAddr::new(addr.ip(), http_port);
+                        tokio::spawn(async move {
+                            if let Err(err) = tls::serve_redirect(http_addr, addr.port()).await {
+                                error!("redirect server error: {}", err);
                             }
-                            Some(_) | None => {
-                                // return a 401 reply.
-                                let response = hyper::Response::builder()
-                                    .status(401)
-                                    .header(
-                                        "WWW-Authenticate",
-                                        "Basic realm=\"webdav\"",
-                                    )
-                                    .body(Body::from("Authentication required".to_string()))
-                                    .unwrap();
-                                return Ok(response);
-                            }
-                        };
-                        let config = DavConfig::new().principal(user);
-                        Ok::<_, Infallible>(dav_server.handle_with(config, req).await)
-                    } else {
-                        Ok::<_, Infallible>(dav_server.handle(req).await)
+                        });
+                    }
+                    let handler = move |req, peer| app.clone().handle(req, Some(peer));
+                    if let Err(err) = tls::serve(addr, acceptor, handler, shutdown_rx).await {
+                        error!("server error: {}", err);
                     }
                 }
-            };
-            Ok::<_, Infallible>(hyper::service::service_fn(func))
+                _ => {
+                    let make_service = hyper::service::make_service_fn(move |conn: &AddrStream| {
+                        let app = app.clone();
+                        let peer = conn.remote_addr();
+                        async move {
+                            Ok::<_, Infallible>(hyper::service::service_fn(move |req| {
+                                app.clone().handle(req, Some(peer))
+                            }))
+                        }
+                    });
+                    let mut shutdown_rx = shutdown_rx;
+                    let _ = hyper::Server::bind(&addr)
+                        .serve(make_service)
+                        .with_graceful_shutdown(async move {
+                            let _ = shutdown_rx.changed().await;
+                        })
+                        .await
+                        .map_err(|e| error!("server error: {}", e));
+                }
+            }
+            Ok::<_, anyhow::Error>(())
         }
-    });
+    };
+    let deadline = {
+        let mut shutdown_rx = shutdown_rx;
+        let timeout = Duration::from_secs(shutdown_timeout);
+        async move {
+            let _ = shutdown_rx.changed().await;
+            tokio::time::sleep(timeout).await;
+        }
+    };
+    tokio::select! {
+        res = server => res?,
+        _ = deadline => warn!(timeout = shutdown_timeout, "shutdown timeout reached, dropping active transfers"),
+    }
 
-    let _ = hyper::Server::bind(&addr)
-        .serve(make_service)
-        .await
-        .map_err(|e| error!("server error: {}", e));
+    for mount in mounts.iter() {
+        mount.fs.shutdown().await;
+    }
+    info!("shutdown complete");
     Ok(())
 }
+
+/// Resolves on SIGTERM or Ctrl-C.
+async fn wait_for_shutdown() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(err) => {
+                error!(error = %err, "listen for SIGTERM failed");
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Parse the command line and environment, filling the rest in from the config file.
+async fn load_options(matches: &ArgMatches<'static>) -> anyhow::Result<(Opt, Option<(PathBuf, String)>)> {
+    let mut opt = Opt::from_clap(matches);
+    let config = match opt.config.clone() {
+        Some(path) => {
+            let content = tokio::fs::read_to_string(&path)
+                .await
+                .with_context(|| format!("read config file {}", path.display()))?;
+            config::apply(&mut opt, matches, &path, &content)?;
+            Some((path, content))
+        }
+        None => None,
+    };
+    Ok((opt, config))
+}
+
+fn log_filter(opt: &Opt) -> anyhow::Result<EnvFilter> {
+    let directives = match opt.log_level.as_ref() {
+        Some(level) => level.clone(),
+        None => env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LOG_FILTER.to_string()),
+    };
+    EnvFilter::try_new(&directives).with_context(|| format!("invalid log level {}", directives))
+}
+
+async fn load_users(opt: &Opt, config: Option<&(PathBuf, String)>) -> anyhow::Result<Users> {
+    let auth = match (opt.auth_user.clone(), opt.auth_password.clone()) {
+        (Some(user), Some(pwd)) => Some((user, pwd)),
+        (None, None) => None,
+        _ => anyhow::bail!(
+            "{}auth-user and auth-password should be specified together.",
+            opt.config_lines.at(&["auth_user", "auth_password"])
+        ),
+    };
+    let mut users = Users::new(auth);
+    if let Some((path, content)) = config {
+        users.add_file(path, content)?;
+    }
+    if let Some(path) = opt.users_file.as_ref() {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("read users file {}", path.display()))?;
+        users.add_file(path, &content)?;
+    }
+    Ok(users)
+}
+
+/// The accounts to serve, and whether they get mounts of their own.
+async fn load_accounts(opt: &Opt, config: Option<&(PathBuf, String)>) -> anyhow::Result<(Vec<Account>, bool)> {
+    let defaults = AccountDefaults {
+        root: opt.root.clone(),
+        proxy_url: opt.proxy_url.clone(),
+    };
+    let config_accounts = match config {
+        Some((path, content)) => accounts::parse(path, content, &defaults)?,
+        None => Vec::new(),
+    };
+    let multi_account = opt.accounts_file.is_some() || !config_accounts.is_empty();
+    let accounts = match opt.accounts_file.as_ref() {
+        Some(path) => accounts::load(path, &defaults).await?,
+        None if !config_accounts.is_empty() => config_accounts,
+        None => match (opt.pikpak_user.clone(), opt.pikpak_password.clone()) {
+            (Some(username), Some(password)) => vec![Account {
+                name: String::new(),
+                credentials: Credentials { username, password },
+                root: opt.root.clone(),
+                proxy_url: opt.proxy_url.clone(),
+            }],
+            _ => anyhow::bail!(
+                "{}pikpak-user and pikpak-password should be specified unless accounts-file is.",
+                opt.config_lines.at(&["pikpak_user", "pikpak_password"])
+            ),
+        },
+    };
+    Ok((accounts, multi_account))
+}
+
+/// Key signing playback links, none when requests need no credentials anyway.
+fn link_secret(opt: &Opt, users: &Users, generated: &str) -> Option<String> {
+    if users.is_empty() {
+        return None;
+    }
+    Some(opt.link_secret.clone().unwrap_or_else(|| generated.to_string()))
+}
+
+/// Apply the users, account credentials, proxies, cache parameters, link
+/// secret and log level of a re-read configuration. Everything else needs a restart.
+async fn reload_config(
+    matches: &ArgMatches<'static>,
+    app: &App,
+    log_handle: &reload::Handle<EnvFilter, Registry>,
+    generated_link_secret: &str,
+) -> anyhow::Result<()> {
+    let (opt, config) = load_options(matches).await?;
+    let filter = log_filter(&opt)?;
+    let users = load_users(&opt, config.as_ref()).await?;
+    let (accounts, _) = load_accounts(&opt, config.as_ref()).await?;
+
+    log_handle.reload(filter)?;
+    app.link_signer
+        .set_secret(link_secret(&opt, &users, generated_link_secret));
+    *app.users.write().unwrap() = if users.is_empty() { None } else { Some(users) };
+    for mount in app.mounts.iter() {
+        if !accounts.iter().any(|account| account.name == mount.name) {
+            warn!(account = %mount.name, "account removed from configuration, restart to stop serving it");
+        }
+    }
+    for account in accounts {
+        match app.mounts.iter().find(|mount| mount.name == account.name) {
+            Some(mount) => {
+                mount
+                    .fs
+                    .reconfigure(account.credentials, account.proxy_url, opt.cache_size, opt.cache_ttl)
+                    .await
+            }
+            None => warn!(account = %account.name, "new account in configuration, restart to serve it"),
+        }
+    }
+    Ok(())
+}
+
+/// One PikPak account and the servers attached to it
+#[derive(Clone)]
+struct Mount {
+    name: String,
+    /// `/<name>` on a multi account server, empty otherwise
+    prefix: String,
+    fs: WebdavDriveFileSystem,
+    hls: Option<HlsServer>,
+    link: Option<LinkServer>,
+}
+
+/// Everything a request may be routed to
+#[derive(Clone)]
+struct App {
+    /// Replaced when the configuration is reloaded
+    users: Arc<RwLock<Option<Users>>>,
+    dav_server: DavHandler,
+    mounts: Arc<Vec<Mount>>,
+    /// Listing of the mounts when serving several accounts
+    top: Option<MountsFs>,
+    /// Whether /metrics is served
+    metrics: bool,
+    status: StatusServer,
+    access_log: Option<AccessLog>,
+    /// Shared with the link servers and .strm views, re-keyed on reload
+    link_signer: LinkSigner,
+}
+
+impl App {
+    async fn handle(
+        self,
+        req: hyper::Request<hyper::Body>,
+        client: Option<SocketAddr>,
+    ) -> Result<hyper::Response<LoggedBody>, Infallible> {
+        let method = req.method().clone();
+        let started = Instant::now();
+        let access_log = self.access_log.clone();
+        let entry = access_log.as_ref().map(|_| access_log::Entry::new(&req, client));
+        let mut principal = None;
+        let res = self.dispatch(req, &mut principal).await;
+        res.map(|res| {
+            let status = res.status().as_u16();
+            metrics::request(method.as_str(), status, started.elapsed());
+            // logged once the body is sent
+            let pending = match (access_log, entry) {
+                (Some(access_log), Some(mut entry)) => {
+                    entry.respond(principal, status);
+                    Some((access_log, entry, started))
+                }
+                _ => None,
+            };
+            res.map(|body| LoggedBody::new(body, pending))
+        })
+    }
+
+    /// Route `req`, setting `principal` to the user it authenticated as.
+    async fn dispatch(
+        self,
+        req: hyper::Request<hyper::Body>,
+        principal: &mut Option<String>,
+    ) -> Result<hyper::Response<Body>, Infallible> {
+        let App {
+            users,
+            dav_server,
+            mounts,
+            top,
+            metrics,
+            status,
+            access_log: _,
+            link_signer: _,
+        } = self;
+        // probes come often and without credentials, answer before running bcrypt
+        if let Some(res) = status::healthz(&req) {
+            return Ok(res);
+        }
+        let users = users.read().unwrap().clone();
+        let mut config = DavConfig::new();
+        let mut authorized = true;
+        let mut user_root = "/".to_string();
+        let mut permissions = Permissions::default();
+        if let Some(users) = users.as_ref() {
+            let user = match req.headers().typed_get::<Authorization<Basic>>() {
+                Some(Authorization(basic)) => users.authenticate(basic.username(), basic.password()).await,
+                None => None,
+            };
+            match user {
+                Some(user) => {
+                    user_root = user.root;
+                    permissions = user.permissions;
+                    *principal = Some(user.name.clone());
+                    config = config.principal(user.name);
+                }
+                None => authorized = false,
+            }
+        }
+        // with several accounts a user root starts with the account it is in
+        let user_mount = match top.as_ref() {
+            Some(_) => accounts::split_mount(&user_root).map(|(name, rest)| (name.to_string(), rest.to_string())),
+            None => None,
+        };
+        let path = req.uri().path().to_string();
+        let mount = match top.as_ref() {
+            Some(_) => accounts::split_mount(&path)
+                .and_then(|(name, _)| mounts.iter().find(|m| m.name == name)),
+            None => mounts.first(),
+        };
+        let rel_path = match mount {
+            Some(mount) if path.len() > mount.prefix.len() => path[mount.prefix.len()..].to_string(),
+            _ => "/".to_string(),
+        };
+        if !authorized {
+            if status.is_public() && status.matches(&req) {
+                let mounts = mounts.iter().map(|m| (m.name.as_str(), &m.fs)).collect::<Vec<_>>();
+                return Ok(status.handle(&mounts, false).await);
+            }
+            if let Some(link) = mount.and_then(|m| m.link.as_ref()) {
+                // signed playback links work without credentials
+                if link.matches(&rel_path) {
+                    let req = strip_mount_prefix(req, &mount.unwrap().prefix);
+                    return Ok(link.handle(&req).await);
+                }
+            }
+            // return a 401 reply.
+            let response = hyper::Response::builder()
+                .status(401)
+                .header(
+                    "WWW-Authenticate",
+                    "Basic realm=\"webdav\"",
+                )
+                .body(Body::from("Authentication required".to_string()))
+                .unwrap();
+            return Ok(response);
+        }
+        if metrics && path == "/metrics" && req.method() == hyper::Method::GET {
+            return Ok(metrics::response());
+        }
+        if status.matches(&req) {
+            // users confined to an account only see that one
+            let mounts = mounts
+                .iter()
+                .filter(|m| user_mount.as_ref().map_or(true, |(name, _)| *name == m.name))
+                .map(|m| (m.name.as_str(), &m.fs))
+                .collect::<Vec<_>>();
+            return Ok(status.handle(&mounts, true).await);
+        }
+        let mount = match (mount, top) {
+            (Some(mount), _) => mount,
+            (None, Some(top)) => {
+                let top = match user_mount.as_ref() {
+                    Some((name, _)) => top.only(name),
+                    None => top,
+                };
+                config = config.filesystem(Box::new(top));
+                return Ok(dav_server.handle_with(config, req).await);
+            }
+            (None, None) => {
+                let response = hyper::Response::builder()
+                    .status(404)
+                    .body(Body::from("Not found".to_string()))
+                    .unwrap();
+                return Ok(response);
+            }
+        };
+        if let Some((name, rest)) = user_mount {
+            if name != mount.name {
+                let response = hyper::Response::builder()
+                    .status(403)
+                    .body(Body::from("Forbidden".to_string()))
+                    .unwrap();
+                return Ok(response);
+            }
+            user_root = rest;
+        }
+        if let Some(link) = mount.link.as_ref() {
+            // still needs a signature, only files below the user's root are signed for them
+            if link.matches(&rel_path) {
+                let req = strip_mount_prefix(req, &mount.prefix);
+                return Ok(link.handle(&req).await);
+            }
+        }
+        // every request of the user sees only their part of the drive
+        let dav_fs = mount.fs.for_user(&user_root, permissions);
+        config = config.filesystem(Box::new(dav_fs.clone()));
+        if !mount.prefix.is_empty() {
+            config = config.strip_prefix(mount.prefix.clone());
+        }
+        if let Some(hls) = mount.hls.as_ref() {
+            let hls = hls.with_fs(dav_fs.clone());
+            if hls.matches(&rel_path).await {
+                let req = strip_mount_prefix(req, &mount.prefix);
+                return Ok(hls.handle(&req).await);
+            }
+        }
+        let permissions = dav_fs.permissions();
+        if !permissions.can_write() && req.method().as_str() == "LOCK" {
+            // nothing to protect from concurrent writers
+            let response = hyper::Response::builder()
+                .status(403)
+                .body(Body::from("Forbidden".to_string()))
+                .unwrap();
+            return Ok(response);
+        }
+        let thumbnail = ThumbnailServer::new(dav_fs.clone());
+        if thumbnail.matches(&req) {
+            let req = strip_mount_prefix(req, &mount.prefix);
+            return Ok(thumbnail.handle(&req).await);
+        }
+        let (req, moved_props) = if req.method().as_str() == "PROPPATCH" {
+            match map_proppatch(req).await {
+                Ok(mapped) => mapped,
+                Err(res) => return Ok(res),
+            }
+        } else {
+            (req, Vec::new())
+        };
+        let is_upload = req.method() == hyper::Method::PUT;
+        let upload_mtime = if is_upload {
+            sidecar::mtime_from_headers(req.headers())
+        } else {
+            None
+        };
+        let path = rel_path;
+        let is_download = req.method() == hyper::Method::GET || req.method() == hyper::Method::HEAD;
+        let is_options = req.method() == hyper::Method::OPTIONS;
+        let mut res = dav_server.handle_with(config, req).await;
+        if is_options {
+            advertise_permissions(permissions, &mut res);
+        }
+        if !moved_props.is_empty() {
+            res = unmap_proppatch(res, &moved_props).await;
+        }
+        if is_download && res.status().is_success() {
+            add_checksum_header(&dav_fs, &path, &mut res).await;
+        }
+        if is_upload && res.status().is_success() {
+            if let Some(upload_mtime) = upload_mtime {
+                record_upload_mtime(&dav_fs, &path, upload_mtime, &mut res).await;
+            }
+            set_upload_etag(&dav_fs, &path, &mut res).await;
+        }
+        Ok::<_, Infallible>(res)
+    }
+}
+
+/// `req` as the servers of a mount see it, without the mount prefix.
+fn strip_mount_prefix(req: hyper::Request<hyper::Body>, prefix: &str) -> hyper::Request<hyper::Body> {
+    if prefix.is_empty() {
+        return req;
+    }
+    let (mut parts, body) = req.into_parts();
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let stripped = match path_and_query.strip_prefix(prefix) {
+        Some(rest) if rest.starts_with('/') => rest.to_string(),
+        Some(rest) => format!("/{}", rest),
+        None => path_and_query.to_string(),
+    };
+    if let Ok(uri) = stripped.parse() {
+        parts.uri = uri;
+    }
+    hyper::Request::from_parts(parts, body)
+}
+
+/// Leave what `permissions` forbid out of the OPTIONS answer, so clients
+/// such as Finder mount read-only shares read-only.
+fn advertise_permissions(permissions: Permissions, res: &mut hyper::Response<Body>) {
+    let denied: &[&str] = if !permissions.can_write() {
+        &["PUT", "PATCH", "MKCOL", "COPY", "MOVE", "DELETE", "LOCK", "UNLOCK", "PROPPATCH"]
+    } else if !permissions.can_delete() {
+        &["DELETE"]
+    } else {
+        return;
+    };
+    let headers = res.headers_mut();
+    if let Some(allow) = headers.get("allow").and_then(|v| v.to_str().ok()) {
+        let allow = allow
+            .split(',')
+            .map(str::trim)
+            .filter(|m| !denied.contains(m))
+            .collect::<Vec<_>>()
+            .join(",");
+        if let Ok(value) = hyper::header::HeaderValue::from_str(&allow) {
+            headers.insert("allow", value);
+        }
+    }
+    if !permissions.can_write() {
+        // class 2 is locking, which only writers need
+        headers.insert("DAV", hyper::header::HeaderValue::from_static("1,3"));
+    }
+}
+
+/// Let PROPPATCH of DAV: modification times through to the filesystem, see `props::map_mtime_props`.
+/// Returns the request with the names of the moved properties, or the error to answer with.
+async fn map_proppatch(
+    req: hyper::Request<hyper::Body>,
+) -> Result<(hyper::Request<hyper::Body>, Vec<String>), hyper::Response<Body>> {
+    use hyper::body::HttpBody;
+
+    let too_large = || {
+        hyper::Response::builder()
+            .status(413)
+            .body(Body::from("Request body too large".to_string()))
+            .unwrap()
+    };
+    let (parts, mut body) = req.into_parts();
+    if body.size_hint().lower() > MAX_PROPPATCH_BODY as u64 {
+        return Err(too_large());
+    }
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) if buffer.len() + chunk.len() <= MAX_PROPPATCH_BODY => buffer.extend_from_slice(&chunk),
+            Ok(_) => return Err(too_large()),
+            Err(err) => {
+                error!(error = %err, "read proppatch body failed");
+                return Err(hyper::Response::builder()
+                    .status(400)
+                    .body(Body::from("Bad request".to_string()))
+                    .unwrap());
+            }
+        }
+    }
+    let (body, moved) = match props::map_mtime_props(&buffer) {
+        Some((mapped, moved)) => (mapped, moved),
+        None => (buffer, Vec::new()),
+    };
+    Ok((hyper::Request::from_parts(parts, hyper::Body::from(body)), moved))
+}
+
+/// Answer for the moved properties under the names the client used.
+async fn unmap_proppatch(res: hyper::Response<Body>, moved: &[String]) -> hyper::Response<Body> {
+    let (mut parts, body) = res.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => {
+            error!(error = %err, "read proppatch response failed");
+            return hyper::Response::from_parts(parts, Body::from(String::new()));
+        }
+    };
+    let body = match props::unmap_mtime_props(&body, moved) {
+        Some(unmapped) => bytes::Bytes::from(unmapped),
+        None => body,
+    };
+    parts.headers.remove(hyper::header::CONTENT_LENGTH);
+    hyper::Response::from_parts(parts, Body::from(body))
+}
+
+/// Keep the modification time sent with an upload, acknowledged the way ownCloud does.
+async fn record_upload_mtime(
+    fs: &WebdavDriveFileSystem,
+    path: &str,
+    mtime: std::time::SystemTime,
+    res: &mut hyper::Response<Body>,
+) {
+    let dav_path = match DavPath::new(path) {
+        Ok(dav_path) => dav_path,
+        Err(_) => return,
+    };
+    match fs.record_mtime(&dav_path, mtime).await {
+        Ok(_) => {
+            res.headers_mut()
+                .insert("X-OC-MTime", hyper::header::HeaderValue::from_static("accepted"));
+        }
+        Err(err) => error!(path = %path, error = ?err, "record upload mtime failed"),
+    }
+}
+
+/// The handler tags a PUT response with the metadata it opened the file with,
+/// replace that with the tag of the content PikPak now holds.
+async fn set_upload_etag(fs: &WebdavDriveFileSystem, path: &str, res: &mut hyper::Response<Body>) {
+    res.headers_mut().remove(hyper::header::ETAG);
+    let dav_path = match DavPath::new(path) {
+        Ok(dav_path) => dav_path,
+        Err(_) => return,
+    };
+    let etag = match fs.lookup(&dav_path).await {
+        Ok(Some(file)) => file.etag(),
+        _ => None,
+    };
+    if let Some(etag) = etag {
+        if let Ok(value) = hyper::header::HeaderValue::from_str(&format!("\"{}\"", etag)) {
+            res.headers_mut().insert(hyper::header::ETAG, value.clone());
+            res.headers_mut().insert("OC-ETag", value);
+        }
+    }
+}
+
+/// ownCloud clients verify downloads against `OC-Checksum`.
+async fn add_checksum_header(fs: &WebdavDriveFileSystem, path: &str, res: &mut hyper::Response<Body>) {
+    let dav_path = match DavPath::new(path) {
+        Ok(dav_path) => dav_path,
+        Err(_) => return,
+    };
+    if let Some(checksum) = fs.checksums(&dav_path).await.first() {
+        if let Ok(value) = hyper::header::HeaderValue::from_str(&checksum.to_string()) {
+            res.headers_mut().insert("OC-Checksum", value);
+        }
+    }
+}
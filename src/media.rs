@@ -0,0 +1,138 @@
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::model::{Media, WebdavFile};
+
+/// Which stream `get_download_url` hands out for video files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaPolicy {
+    /// The uploaded file as is
+    Original,
+    /// One of PikPak's transcoded streams, falling back to the original
+    Transcoded,
+}
+
+impl FromStr for MediaPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "original" => Ok(Self::Original),
+            "transcoded" => Ok(Self::Transcoded),
+            _ => Err(format!("unknown media policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaConfig {
+    pub policy: MediaPolicy,
+    /// Preferred transcode label such as `720P`, the default stream when unset
+    pub quality: Option<String>,
+    /// Labels exposed as virtual `name [label].mp4` siblings of every video
+    pub variants: Vec<String>,
+}
+
+impl MediaConfig {
+    /// Pick the download url of `file` according to the policy.
+    pub fn select_url(&self, file: &WebdavFile) -> Option<String> {
+        if !file.mime_type.contains("video/") {
+            return non_empty(&file.web_content_link);
+        }
+        match self.policy {
+            MediaPolicy::Original => non_empty(&file.web_content_link)
+                .or_else(|| file.medias.iter().find(|m| m.is_origin).and_then(media_url)),
+            MediaPolicy::Transcoded => self
+                .transcode_url(file, self.quality.as_deref())
+                .or_else(|| non_empty(&file.web_content_link))
+                .or_else(|| file.medias.first().and_then(media_url)),
+        }
+    }
+
+    /// Whether the policy may hand out a transcode of `file`, which only its detail medias tell.
+    pub fn may_transcode(&self, file: &WebdavFile) -> bool {
+        self.policy == MediaPolicy::Transcoded && file.mime_type.contains("video/")
+    }
+
+    /// Whether `select_url` hands out the content of `file` itself rather than a transcode.
+    pub fn selects_original(&self, file: &WebdavFile) -> bool {
+        !self.may_transcode(file) || self.transcode_url(file, self.quality.as_deref()).is_none()
+    }
+
+    /// Url of the transcode labelled `quality`, or the default transcode.
+    pub fn transcode_url(&self, file: &WebdavFile, quality: Option<&str>) -> Option<String> {
+        let transcodes = || file.medias.iter().filter(|m| !m.is_origin);
+        quality
+            .and_then(|quality| find_media(&file.medias, quality))
+            .or_else(|| transcodes().find(|m| m.is_default))
+            .or_else(|| transcodes().next())
+            .and_then(media_url)
+    }
+
+    /// Url of the transcode labelled `label`, used by virtual variant entries.
+    pub fn variant_url(&self, file: &WebdavFile, label: &str) -> Option<String> {
+        find_media(&file.medias, label).and_then(media_url)
+    }
+
+    /// Size of the transcode labelled `label`, if the medias of `file` tell it.
+    pub fn variant_size(&self, file: &WebdavFile, label: &str) -> Option<u64> {
+        find_media(&file.medias, label).and_then(|m| m.size)
+    }
+
+    /// Virtual sibling entries for the videos in a directory listing.
+    pub fn variants_of(&self, files: &[WebdavFile]) -> Vec<WebdavFile> {
+        if self.variants.is_empty() {
+            return Vec::new();
+        }
+        files
+            .iter()
+            .filter(|f| has_variants(f))
+            .flat_map(|f| self.variants.iter().map(move |label| variant_file(f, label)))
+            .collect()
+    }
+
+    /// The virtual entry called `name` among the variants of `files`.
+    pub fn variant_named(&self, files: &[WebdavFile], name: &str) -> Option<WebdavFile> {
+        let (original, label) = name.strip_suffix("].mp4")?.rsplit_once(" [")?;
+        let label = self.variants.iter().find(|l| *l == label)?;
+        files
+            .iter()
+            .find(|f| f.name == original && has_variants(f))
+            .map(|f| variant_file(f, label))
+    }
+}
+
+fn has_variants(file: &WebdavFile) -> bool {
+    file.variant.is_none() && file.kind == "drive#file" && file.mime_type.contains("video/")
+}
+
+/// The size is the original's until the transcode's is known.
+fn variant_file(file: &WebdavFile, label: &str) -> WebdavFile {
+    let mut variant = file.clone();
+    if let Some(size) = find_media(&file.medias, label).and_then(|m| m.size) {
+        variant.size = size.to_string();
+    }
+    variant.name = format!("{} [{}].mp4", file.name, label);
+    variant.file_extension = ".mp4".to_string();
+    variant.mime_type = "video/mp4".to_string();
+    variant.variant = Some(label.to_string());
+    variant
+}
+
+fn find_media<'a>(medias: &'a [Media], label: &str) -> Option<&'a Media> {
+    medias.iter().find(|m| m.label().eq_ignore_ascii_case(label))
+}
+
+fn media_url(media: &Media) -> Option<String> {
+    non_empty(&media.link.url)
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s.to_string())
+    }
+}
@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+use dav_server::body::Body;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder,
+    HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+
+lazy_static! {
+    static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "pikpak_webdav_requests_total",
+        "WebDAV requests by method and response status",
+        &["method", "status"]
+    )
+    .unwrap();
+    static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "pikpak_webdav_request_duration_seconds",
+        "Time until the response headers of a WebDAV request were ready",
+        &["method"]
+    )
+    .unwrap();
+    static ref API_CALLS: IntCounterVec = register_int_counter_vec!(
+        "pikpak_webdav_api_requests_total",
+        "PikPak and OSS API calls by endpoint and status, status is error when no response came back",
+        &["endpoint", "status"]
+    )
+    .unwrap();
+    static ref TOKEN_REFRESHES: IntCounterVec = register_int_counter_vec!(
+        "pikpak_webdav_token_refreshes_total",
+        "PikPak sign-ins by result",
+        &["result"]
+    )
+    .unwrap();
+    static ref CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "pikpak_webdav_cache_lookups_total",
+        "Directory cache lookups by result",
+        &["result"]
+    )
+    .unwrap();
+    static ref TRANSFER_BYTES: IntCounterVec = register_int_counter_vec!(
+        "pikpak_webdav_transfer_bytes_total",
+        "File content bytes moved through the server by direction",
+        &["direction"]
+    )
+    .unwrap();
+    static ref ACTIVE_TRANSFERS: IntGaugeVec = register_int_gauge_vec!(
+        "pikpak_webdav_active_transfers",
+        "Files currently being read or written by direction",
+        &["direction"]
+    )
+    .unwrap();
+}
+
+/// Methods get their own label value, anything else is counted as OTHER
+const METHODS: &[&str] = &[
+    "GET", "HEAD", "PUT", "POST", "DELETE", "OPTIONS", "PROPFIND", "PROPPATCH", "MKCOL", "COPY",
+    "MOVE", "LOCK", "UNLOCK",
+];
+
+pub const UPLOAD: &str = "upload";
+pub const DOWNLOAD: &str = "download";
+
+pub fn request(method: &str, status: u16, duration: Duration) {
+    let method = METHODS.iter().find(|m| **m == method).copied().unwrap_or("OTHER");
+    REQUESTS
+        .with_label_values(&[method, &status.to_string()])
+        .inc();
+    REQUEST_DURATION
+        .with_label_values(&[method])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn api_call(method: &reqwest::Method, url: &reqwest::Url, res: &reqwest::Result<reqwest::Response>) {
+    let status = match res {
+        Ok(res) => res.status().as_u16().to_string(),
+        Err(err) => match err.status() {
+            Some(status) => status.as_u16().to_string(),
+            None => "error".to_string(),
+        },
+    };
+    API_CALLS
+        .with_label_values(&[&endpoint(method, url), &status])
+        .inc();
+}
+
+/// Host and path of an API url with file ids and upload keys left out, so
+/// the label stays bounded. Requests through a proxy carry the target url
+/// in their path.
+fn endpoint(method: &reqwest::Method, url: &reqwest::Url) -> String {
+    let target = url
+        .path()
+        .find("/https://")
+        .and_then(|pos| reqwest::Url::parse(&url.path()[pos + 1..]).ok());
+    let url = target.as_ref().unwrap_or(url);
+    let host = url.host_str().unwrap_or_default();
+    if host.contains("aliyuncs.com") || !host.ends_with("mypikpak.com") {
+        // OSS object keys are random, the operation is in the query
+        let query = url.query().unwrap_or_default();
+        let operation = if query == "uploads" {
+            "initiate"
+        } else if query.contains("partNumber") {
+            "part"
+        } else if *method == reqwest::Method::DELETE {
+            "abort"
+        } else {
+            "complete"
+        };
+        return format!("oss:{}", operation);
+    }
+    let path = url
+        .path_segments()
+        .map(|segments| {
+            segments
+                .map(|segment| match segment.find(':') {
+                    // e.g. files:batchDelete
+                    Some(_) => segment,
+                    None if segment.len() > 16 => ":id",
+                    None => segment,
+                })
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .unwrap_or_default();
+    format!("{}/{}", host, path)
+}
+
+pub fn token_refresh(ok: bool) {
+    TOKEN_REFRESHES
+        .with_label_values(&[if ok { "ok" } else { "error" }])
+        .inc();
+}
+
+pub fn cache_lookup(hit: bool) {
+    CACHE_LOOKUPS
+        .with_label_values(&[if hit { "hit" } else { "miss" }])
+        .inc();
+}
+
+pub fn transferred(direction: &'static str, bytes: usize) {
+    TRANSFER_BYTES
+        .with_label_values(&[direction])
+        .inc_by(bytes as u64);
+}
+
+/// Counts as an active transfer until dropped.
+#[derive(Debug)]
+pub struct Transfer(&'static str);
+
+impl Transfer {
+    pub fn start(direction: &'static str) -> Self {
+        ACTIVE_TRANSFERS.with_label_values(&[direction]).inc();
+        Self(direction)
+    }
+}
+
+/// Number of transfers in `direction` right now.
+pub fn active_transfers(direction: &'static str) -> i64 {
+    ACTIVE_TRANSFERS.with_label_values(&[direction]).get()
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        ACTIVE_TRANSFERS.with_label_values(&[self.0]).dec();
+    }
+}
+
+/// All metrics in the Prometheus text format.
+pub fn response() -> hyper::Response<Body> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        return hyper::Response::builder()
+            .status(500)
+            .body(Body::from(err.to_string()))
+            .unwrap();
+    }
+    hyper::Response::builder()
+        .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(bytes::Bytes::from(buffer)))
+        .unwrap()
+}
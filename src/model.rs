@@ -3,7 +3,7 @@ use ::time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, Context, Result};
 use bytes::Bytes;
@@ -13,7 +13,7 @@ use reqwest::{
     StatusCode,
 };
 use serde::de::DeserializeOwned;
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio::{
     sync::{oneshot, RwLock},
     time,
@@ -22,7 +22,7 @@ use tracing::{debug, error, info, warn};
 use dav_server::fs::{DavDirEntry, DavMetaData, FsFuture, FsResult};
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Credentials {
     pub username: String,
     pub password: String,
@@ -62,6 +62,28 @@ pub struct RenameFileRequest<'a>{
     pub name: &'a str,
 }
 
+/// Attributes changed through PROPPATCH, unset fields are left alone
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpdateFileRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starred: Option<bool>,
+}
+
+impl UpdateFileRequest {
+    pub fn is_empty(&self) -> bool {
+        self.modified_time.is_none()
+            && self.description.is_none()
+            && self.tags.is_none()
+            && self.starred.is_none()
+    }
+}
+
 
 
 
@@ -74,23 +96,36 @@ pub struct RefreshTokenResponse {
 }
 
 
-#[derive(Debug, Clone,Serialize)]
+#[derive(Debug, Clone)]
 pub struct DateTime(SystemTime);
 
 impl DateTime {
     pub fn new(st: SystemTime) -> Self {
         Self(st)
     }
+
+    pub fn to_rfc3339(&self) -> String {
+        OffsetDateTime::from(self.0).format(&Rfc3339).unwrap_or_default()
+    }
 }
 
 impl<'a> Deserialize<'a> for DateTime {
     fn deserialize<D: Deserializer<'a>>(deserializer: D) -> Result<Self, D::Error> {
-        let dt = OffsetDateTime::parse(<&str>::deserialize(deserializer)?, &Rfc3339)
+        let dt = OffsetDateTime::parse(&String::deserialize(deserializer)?, &Rfc3339)
             .map_err(serde::de::Error::custom)?;
         Ok(Self(dt.into()))
     }
 }
 
+impl Serialize for DateTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let dt = OffsetDateTime::from(self.0)
+            .format(&Rfc3339)
+            .map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&dt)
+    }
+}
+
 impl ops::Deref for DateTime {
     type Target = SystemTime;
 
@@ -226,6 +261,56 @@ pub struct WebdavFile {
     pub modified_time: DateTime,
     pub medias:Vec<Media>,
     pub hash: Option<String>,
+    #[serde(default)]
+    pub thumbnail_link: String,
+    #[serde(default)]
+    pub icon_link: String,
+    #[serde(default)]
+    pub md5_checksum: String,
+    #[serde(default)]
+    pub revision: String,
+    #[serde(default)]
+    pub starred: bool,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default, deserialize_with = "deserialize_tags")]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub audit: Option<FileAudit>,
+    /// e.g. `SHARED`, empty for files not shared by link
+    #[serde(default)]
+    pub share_status: String,
+    /// Transcode label of a virtual `name [label].mp4` entry
+    #[serde(skip)]
+    pub variant: Option<String>,
+    /// Opened for reading while the media policy serves a transcode in its place
+    #[serde(skip)]
+    pub transcoded: bool,
+}
+
+/// Tags come as plain strings or as objects with a `name`, anything else is ignored.
+fn deserialize_tags<'a, D: Deserializer<'a>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+    let tags = match value {
+        Some(serde_json::Value::Array(tags)) => tags,
+        _ => return Ok(Vec::new()),
+    };
+    Ok(tags
+        .into_iter()
+        .filter_map(|tag| match tag {
+            serde_json::Value::String(name) => Some(name),
+            serde_json::Value::Object(obj) => obj.get("name").and_then(|n| n.as_str()).map(String::from),
+            _ => None,
+        })
+        .collect())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileAudit {
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub message: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -237,6 +322,52 @@ pub struct Link {
 pub struct Media {
     pub media_name: String,
     pub link:Link,
+    #[serde(default)]
+    pub resolution_name: String,
+    #[serde(default)]
+    pub is_origin: bool,
+    #[serde(default)]
+    pub is_default: bool,
+    #[serde(default)]
+    pub video: Option<MediaVideo>,
+    /// Bytes of the stream, once known
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub size: Option<u64>,
+}
+
+/// Sizes come as strings like everywhere else in the API, or as numbers.
+fn deserialize_size<'a, D: Deserializer<'a>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(match value {
+        Some(serde_json::Value::String(size)) => size.parse().ok(),
+        Some(serde_json::Value::Number(size)) => size.as_u64(),
+        _ => None,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaVideo {
+    #[serde(default)]
+    pub height: u64,
+    #[serde(default)]
+    pub width: u64,
+    #[serde(default)]
+    pub duration: u64,
+    #[serde(default)]
+    pub bit_rate: u64,
+    #[serde(default)]
+    pub video_codec: String,
+}
+
+impl Media {
+    /// Quality label shown to users, e.g. `720P`
+    pub fn label(&self) -> &str {
+        if self.resolution_name.is_empty() {
+            &self.media_name
+        } else {
+            &self.resolution_name
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -268,6 +399,29 @@ impl DavMetaData for WebdavFile {
     fn created(&self) -> FsResult<SystemTime> {
         Ok(*self.created_time)
     }
+
+    /// Files are tagged by content hash, or id and revision when PikPak has no hash,
+    /// with a suffix when a transcode is served in their place.
+    fn etag(&self) -> Option<String> {
+        if self.kind == "drive#folder" {
+            let t = self.modified_time.duration_since(UNIX_EPOCH).ok()?;
+            return Some(format!("{:x}", t.as_micros()));
+        }
+        if self.id.is_empty() {
+            // upload in progress, the content isn't known yet
+            return None;
+        }
+        let tag = match self.hash.as_deref().filter(|h| !h.is_empty()) {
+            Some(hash) => hash.to_ascii_lowercase(),
+            None if !self.revision.is_empty() => format!("{}-{}", self.id, self.revision),
+            None => format!("{}-{:x}", self.id, self.len()),
+        };
+        match self.variant.as_ref() {
+            Some(label) => Some(format!("{}-{}", tag, label)),
+            None if self.transcoded => Some(format!("{}-transcoded", tag)),
+            None => Some(tag),
+        }
+    }
 }
 
 impl DavDirEntry for WebdavFile {
@@ -297,6 +451,17 @@ impl WebdavFile {
             web_content_link: "".to_string(),
             medias:Vec::new(),
             hash: Some("".to_string()),
+            thumbnail_link: "".to_string(),
+            icon_link: "".to_string(),
+            md5_checksum: "".to_string(),
+            revision: "".to_string(),
+            starred: false,
+            description: "".to_string(),
+            tags: Vec::new(),
+            audit: None,
+            share_status: "".to_string(),
+            variant: None,
+            transcoded: false,
         }
     }
 }
@@ -0,0 +1,236 @@
+use std::io::Cursor;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ::time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use dav_server::fs::DavProp;
+use hyper::StatusCode;
+use xmltree::{Element, XMLNode};
+
+use crate::model::{DateTime, MediaVideo, UpdateFileRequest, WebdavFile};
+
+/// Namespace of the PikPak specific WebDAV properties
+pub const PIKPAK_NS: &str = "https://mypikpak.com/ns";
+const PIKPAK_PREFIX: &str = "pk";
+const DAV_NS: &str = "DAV:";
+/// DAV: modification time properties clients PROPPATCH, `lastmodified` is the ownCloud flavour
+const MTIME_PROPS: &[&str] = &["getlastmodified", "lastmodified"];
+
+pub fn is_pikpak(prop: &DavProp) -> bool {
+    prop.namespace.as_deref() == Some(PIKPAK_NS)
+}
+
+/// PikPak properties listed for PROPFIND allprop/propname
+pub const PIKPAK_PROPS: &[&str] = &[
+    "id",
+    "parent-id",
+    "phase",
+    "mime-type",
+    "md5",
+    "gcid",
+    "duration",
+    "resolution",
+    "created",
+    "modified",
+    "starred",
+    "description",
+    "tags",
+    "share-status",
+    "audit-status",
+    "thumbnail",
+    "icon",
+];
+
+/// Value of the PikPak property `name` of `file`, if it has one.
+pub fn pikpak_prop(file: &WebdavFile, name: &str) -> Option<String> {
+    let value = match name {
+        "id" => file.id.clone(),
+        "parent-id" => file.parent_id.clone(),
+        "phase" => file.phase.clone(),
+        "mime-type" => file.mime_type.clone(),
+        "md5" => file.md5_checksum.clone(),
+        // PikPak's `hash` is the gcid of the content
+        "gcid" => file.hash.clone().unwrap_or_default(),
+        "duration" => video(file).map(|v| v.duration.to_string()).unwrap_or_default(),
+        "resolution" => video(file)
+            .map(|v| format!("{}x{}", v.width, v.height))
+            .unwrap_or_default(),
+        "created" => file.created_time.to_rfc3339(),
+        "modified" => file.modified_time.to_rfc3339(),
+        "starred" => file.starred.to_string(),
+        "description" => file.description.clone(),
+        "tags" => file.tags.join(","),
+        "share-status" => file.share_status.clone(),
+        "audit-status" => file.audit.as_ref().map(|a| a.status.clone()).unwrap_or_default(),
+        "thumbnail" => file.thumbnail_link.clone(),
+        "icon" => file.icon_link.clone(),
+        _ => return None,
+    };
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// All PikPak properties `file` has, with values unless only names are asked for.
+pub fn pikpak_props(file: &WebdavFile, do_content: bool) -> Vec<DavProp> {
+    PIKPAK_PROPS
+        .iter()
+        .filter_map(|name| pikpak_prop(file, name).map(|value| (name, value)))
+        .map(|(name, value)| DavProp {
+            name: name.to_string(),
+            prefix: Some(PIKPAK_PREFIX.to_string()),
+            namespace: Some(PIKPAK_NS.to_string()),
+            xml: if do_content {
+                Some(prop_xml(name, &value))
+            } else {
+                None
+            },
+        })
+        .collect()
+}
+
+/// Stream details of the original upload, or of the best transcode.
+fn video(file: &WebdavFile) -> Option<&MediaVideo> {
+    let medias = || file.medias.iter().filter(|m| m.video.is_some());
+    medias()
+        .find(|m| m.is_origin)
+        .or_else(|| medias().max_by_key(|m| m.video.as_ref().map(|v| v.height)))
+        .and_then(|m| m.video.as_ref())
+        .filter(|v| v.width > 0 && v.height > 0)
+}
+
+/// Record a PROPPATCH `set` or `remove` of `prop` in `req`, returning the status of that property.
+pub fn apply_patch(req: &mut UpdateFileRequest, set: bool, prop: &DavProp) -> StatusCode {
+    if !is_pikpak(prop) {
+        return StatusCode::FORBIDDEN;
+    }
+    let text = prop_text(prop);
+    match (prop.name.as_str(), set) {
+        ("getlastmodified", true) | ("lastmodified", true) | ("modified", true) => {
+            match parse_mtime(&prop.name, &text) {
+                Some(mtime) => req.modified_time = Some(DateTime::new(mtime).to_rfc3339()),
+                None => return StatusCode::CONFLICT,
+            }
+        }
+        ("description", true) => req.description = Some(text),
+        ("description", false) => req.description = Some(String::new()),
+        ("tags", true) => {
+            let tags = text
+                .split(',')
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect();
+            req.tags = Some(tags);
+        }
+        ("tags", false) => req.tags = Some(Vec::new()),
+        ("starred", true) => match text.to_ascii_lowercase().as_str() {
+            "true" | "1" => req.starred = Some(true),
+            "false" | "0" => req.starred = Some(false),
+            _ => return StatusCode::CONFLICT,
+        },
+        ("starred", false) => req.starred = Some(false),
+        _ => return StatusCode::FORBIDDEN,
+    }
+    StatusCode::OK
+}
+
+/// `getlastmodified` takes an HTTP date, ownCloud's `lastmodified` unix seconds and `modified` RFC 3339.
+fn parse_mtime(name: &str, text: &str) -> Option<SystemTime> {
+    match name {
+        "getlastmodified" => httpdate::parse_http_date(text).ok(),
+        "lastmodified" => text
+            .parse::<u64>()
+            .ok()
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+        _ => OffsetDateTime::parse(text, &Rfc3339).ok().map(SystemTime::from),
+    }
+}
+
+fn prop_text(prop: &DavProp) -> String {
+    prop.xml
+        .as_ref()
+        .and_then(|xml| Element::parse(Cursor::new(xml)).ok())
+        .and_then(|elem| elem.get_text().map(|text| text.trim().to_string()))
+        .unwrap_or_default()
+}
+
+/// dav-server refuses to PROPPATCH DAV: modification times itself, so move them
+/// into the PikPak namespace where `patch_props` gets to handle them.
+/// Returns the rewritten PROPPATCH body and the names of the moved properties
+/// if there was anything to move.
+pub fn map_mtime_props(body: &[u8]) -> Option<(Vec<u8>, Vec<String>)> {
+    let mut tree = Element::parse(Cursor::new(body)).ok()?;
+    let mut moved = Vec::new();
+    for update in tree.children.iter_mut().filter_map(XMLNode::as_mut_element) {
+        for prop in update.children.iter_mut().filter_map(XMLNode::as_mut_element) {
+            for elem in prop.children.iter_mut().filter_map(XMLNode::as_mut_element) {
+                if elem.namespace.as_deref() == Some(DAV_NS) && MTIME_PROPS.contains(&elem.name.as_str()) {
+                    let mut namespaces = elem.namespaces.clone().unwrap_or_else(xmltree::Namespace::empty);
+                    namespaces.put(PIKPAK_PREFIX, PIKPAK_NS);
+                    elem.namespaces = Some(namespaces);
+                    elem.namespace = Some(PIKPAK_NS.to_string());
+                    elem.prefix = Some(PIKPAK_PREFIX.to_string());
+                    if !moved.contains(&elem.name) {
+                        moved.push(elem.name.clone());
+                    }
+                }
+            }
+        }
+    }
+    if moved.is_empty() {
+        return None;
+    }
+    let mut out = Vec::new();
+    tree.write(&mut out).ok()?;
+    Some((out, moved))
+}
+
+/// Put the properties `map_mtime_props` moved back into the DAV: namespace of
+/// the PROPPATCH multistatus, so clients get an answer for what they sent.
+pub fn unmap_mtime_props(body: &[u8], moved: &[String]) -> Option<Vec<u8>> {
+    fn walk(elem: &mut Element, moved: &[String]) {
+        if elem.namespace.as_deref() == Some(PIKPAK_NS) && moved.contains(&elem.name) {
+            let mut namespaces = elem.namespaces.clone().unwrap_or_else(xmltree::Namespace::empty);
+            namespaces.put("D", DAV_NS);
+            elem.namespaces = Some(namespaces);
+            elem.namespace = Some(DAV_NS.to_string());
+            elem.prefix = Some("D".to_string());
+        }
+        for child in elem.children.iter_mut().filter_map(XMLNode::as_mut_element) {
+            walk(child, moved);
+        }
+    }
+    let mut tree = Element::parse(Cursor::new(body)).ok()?;
+    walk(&mut tree, moved);
+    let mut out = Vec::new();
+    tree.write(&mut out).ok()?;
+    Some(out)
+}
+
+/// Serialized `<pk:name>value</pk:name>` element as `get_prop` returns it.
+pub fn prop_xml(name: &str, value: &str) -> Vec<u8> {
+    format!(
+        r#"<?xml version="1.0"?><{pfx}:{name} xmlns:{pfx}="{ns}">{value}</{pfx}:{name}>"#,
+        pfx = PIKPAK_PREFIX,
+        name = name,
+        ns = PIKPAK_NS,
+        value = escape(value),
+    )
+    .into_bytes()
+}
+
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,132 @@
+use std::cmp;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, trace};
+
+/// Prefetched data is handed over in pieces of this size
+const PREFETCH_CHUNK_SIZE: usize = 256 * 1024; // 256KB
+/// Give up on a stream when the CDN sends nothing for this long
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Overrides the client's default request timeout, which would cut long streams
+const STREAM_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Sequential reader over one open-ended Range request, the response body is
+/// read ahead in the background up to `window` bytes.
+pub struct StreamReader {
+    pos: u64,
+    buffer: Bytes,
+    rx: mpsc::Receiver<Result<Bytes>>,
+    task: JoinHandle<()>,
+}
+
+impl StreamReader {
+    pub async fn open(client: &reqwest::Client, url: &str, start_pos: u64, window: usize) -> Result<Self> {
+        debug!(url = %url, start = start_pos, "stream: open");
+        let res = client
+            .get(url)
+            .header(RANGE, format!("bytes={}-", start_pos))
+            .timeout(STREAM_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        if start_pos > 0 && res.status() != StatusCode::PARTIAL_CONTENT {
+            bail!("range request not honoured, got status {}", res.status());
+        }
+        let capacity = cmp::max(window / PREFETCH_CHUNK_SIZE, 1);
+        let (tx, rx) = mpsc::channel(capacity);
+        let task = tokio::spawn(prefetch(res, tx));
+        Ok(Self {
+            pos: start_pos,
+            buffer: Bytes::new(),
+            rx,
+            task,
+        })
+    }
+
+    /// Position of the next byte this reader will return.
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// Read up to `count` bytes, a short read means the end of the file.
+    pub async fn read(&mut self, count: usize) -> Result<Bytes> {
+        let mut buf = BytesMut::with_capacity(count);
+        while buf.len() < count {
+            if self.buffer.is_empty() {
+                match self.rx.recv().await {
+                    Some(Ok(chunk)) => self.buffer = chunk,
+                    Some(Err(err)) => return Err(err),
+                    None => break,
+                }
+            }
+            let len = cmp::min(count - buf.len(), self.buffer.len());
+            buf.extend_from_slice(&self.buffer.split_to(len));
+        }
+        self.pos += buf.len() as u64;
+        trace!(pos = self.pos, count = buf.len(), "stream: read");
+        Ok(buf.freeze())
+    }
+
+    /// Discard `count` bytes, used for short forward seeks. Chunks are dropped
+    /// as they come instead of being copied out.
+    pub async fn skip(&mut self, count: u64) -> Result<()> {
+        let mut left = count;
+        while left > 0 {
+            if self.buffer.is_empty() {
+                match self.rx.recv().await {
+                    Some(Ok(chunk)) => self.buffer = chunk,
+                    Some(Err(err)) => return Err(err),
+                    None => return Err(anyhow!("unexpected end of stream")),
+                }
+            }
+            let len = cmp::min(left, self.buffer.len() as u64) as usize;
+            self.buffer.advance(len);
+            self.pos += len as u64;
+            left -= len as u64;
+        }
+        trace!(pos = self.pos, count = count, "stream: skip");
+        Ok(())
+    }
+}
+
+impl Drop for StreamReader {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn prefetch(mut res: reqwest::Response, tx: mpsc::Sender<Result<Bytes>>) {
+    let mut buf = BytesMut::with_capacity(PREFETCH_CHUNK_SIZE);
+    loop {
+        match time::timeout(STREAM_IDLE_TIMEOUT, res.chunk()).await {
+            Ok(Ok(Some(chunk))) => {
+                buf.extend_from_slice(&chunk);
+                if buf.len() >= PREFETCH_CHUNK_SIZE && tx.send(Ok(buf.split().freeze())).await.is_err() {
+                    // reader is gone
+                    return;
+                }
+            }
+            Ok(Ok(None)) => {
+                if !buf.is_empty() {
+                    let _ = tx.send(Ok(buf.freeze())).await;
+                }
+                return;
+            }
+            Ok(Err(err)) => {
+                let _ = tx.send(Err(err.into())).await;
+                return;
+            }
+            Err(_) => {
+                let _ = tx.send(Err(anyhow!("stream idle timeout"))).await;
+                return;
+            }
+        }
+    }
+}
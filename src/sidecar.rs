@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::HeaderMap;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+
+use crate::model::{DateTime, WebdavFile};
+
+const SIDECAR_FILE_NAME: &str = "sidecar.json";
+/// Upload headers carrying the client's modification time in unix seconds
+const MTIME_HEADERS: &[&str] = &["x-oc-mtime", "x-mtime"];
+
+/// Modification time a client sent along with an upload.
+pub fn mtime_from_headers(headers: &HeaderMap) -> Option<SystemTime> {
+    let value = MTIME_HEADERS
+        .iter()
+        .find_map(|name| headers.get(*name))?
+        .to_str()
+        .ok()?;
+    // some clients send fractional seconds
+    let secs = value.trim().parse::<f64>().ok()?;
+    if !secs.is_finite() || secs < 0.0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/// What we know about a file that PikPak doesn't keep for us
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct FileMeta {
+    /// Modification time the client asked for, unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<u64>,
+    /// SHA-1 the client declared when uploading the content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha1: Option<String>,
+}
+
+/// Client supplied file metadata by file id.
+///
+/// Modification times sent with uploads or PROPPATCH are laid over listings,
+/// upload checksums back `checksums` and conditional uploads. Written to the
+/// workdir when there is one.
+#[derive(Clone)]
+pub struct SidecarStore {
+    path: Option<PathBuf>,
+    files: Arc<Mutex<HashMap<String, FileMeta>>>,
+    // serializes writers of the store file
+    save_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl SidecarStore {
+    pub async fn load(dir: Option<&Path>) -> Self {
+        let path = dir.map(|dir| dir.join(SIDECAR_FILE_NAME));
+        let files = match path.as_ref() {
+            Some(path) => match tokio::fs::read(path).await {
+                Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|err| {
+                    error!(path = %path.display(), error = %err, "sidecar: parse store failed");
+                    HashMap::new()
+                }),
+                Err(_) => HashMap::new(),
+            },
+            None => HashMap::new(),
+        };
+        if let Some(path) = path.as_ref() {
+            info!(path = %path.display(), entries = files.len(), "sidecar: store loaded");
+        }
+        Self {
+            path,
+            files: Arc::new(Mutex::new(files)),
+            save_lock: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    pub async fn set_mtime(&self, file_id: &str, mtime: SystemTime) {
+        let secs = mtime
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        debug!(file_id = %file_id, mtime = secs, "sidecar: set mtime");
+        self.files
+            .lock()
+            .unwrap()
+            .entry(file_id.to_string())
+            .or_default()
+            .mtime = Some(secs);
+        self.save().await;
+    }
+
+    pub fn sha1(&self, file_id: &str) -> Option<String> {
+        let files = self.files.lock().unwrap();
+        files.get(file_id).and_then(|meta| meta.sha1.clone())
+    }
+
+    pub async fn set_sha1(&self, file_id: &str, sha1: &str) {
+        debug!(file_id = %file_id, sha1 = %sha1, "sidecar: set sha1");
+        self.files
+            .lock()
+            .unwrap()
+            .entry(file_id.to_string())
+            .or_default()
+            .sha1 = Some(sha1.to_ascii_lowercase());
+        self.save().await;
+    }
+
+    pub async fn remove(&self, file_id: &str) {
+        if self.files.lock().unwrap().remove(file_id).is_some() {
+            self.save().await;
+        }
+    }
+
+    /// Report the stored times instead of PikPak's in a fresh listing.
+    pub fn apply(&self, files: &mut [WebdavFile]) {
+        let metas = self.files.lock().unwrap();
+        if metas.is_empty() {
+            return;
+        }
+        for file in files.iter_mut() {
+            if let Some(secs) = metas.get(&file.id).and_then(|meta| meta.mtime) {
+                file.modified_time = DateTime::new(UNIX_EPOCH + Duration::from_secs(secs));
+            }
+        }
+    }
+
+    async fn save(&self) {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return,
+        };
+        let _guard = self.save_lock.lock().await;
+        let content = match serde_json::to_vec(&*self.files.lock().unwrap()) {
+            Ok(content) => content,
+            Err(err) => {
+                error!(error = %err, "sidecar: serialize store failed");
+                return;
+            }
+        };
+        let tmp_path = path.with_extension("json.tmp");
+        let res = match tokio::fs::write(&tmp_path, &content).await {
+            Ok(_) => tokio::fs::rename(&tmp_path, path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            error!(path = %path.display(), error = %err, "sidecar: save store failed");
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use dav_server::body::Body;
+use hyper::{Method, Request, Response, StatusCode};
+use moka::future::{Cache as MokaCache, CacheBuilder};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::metrics;
+use crate::vfs::WebdavDriveFileSystem;
+
+pub const HEALTHZ_PATH: &str = "/healthz";
+pub const STATUS_PATH: &str = "/status";
+/// Router pages poll every few seconds, the quota is fetched at most this often
+const QUOTA_TTL: u64 = 60;
+
+#[derive(Debug, Serialize)]
+struct Status {
+    version: &'static str,
+    uptime: u64,
+    accounts: Vec<AccountStatus>,
+    uploads: i64,
+    downloads: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct AccountStatus {
+    /// Mount name, empty when serving a single account
+    name: String,
+    /// Left out of the public status, as is `proxy`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    signed_in: bool,
+    /// Unix time the access token expires
+    token_expires_at: Option<u64>,
+    quota: Option<Quota>,
+    /// Host of the proxy the api requests go through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proxy: Option<String>,
+    cache: CacheStatus,
+    pending_uploads: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Quota {
+    used: u64,
+    total: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheStatus {
+    entries: u64,
+    persisted_bytes: Option<u64>,
+    block_bytes: Option<u64>,
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response<Body> {
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(hyper::header::CACHE_CONTROL, "no-store")
+        .body(Body::from(serde_json::to_string(value).unwrap()))
+        .unwrap()
+}
+
+/// Liveness probe, answered as long as the server accepts requests.
+pub fn healthz(req: &Request<hyper::Body>) -> Option<Response<Body>> {
+    if req.uri().path() != HEALTHZ_PATH || !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return None;
+    }
+    Some(
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "text/plain")
+            .body(Body::from("ok"))
+            .unwrap(),
+    )
+}
+
+/// Serves `/status`, a JSON summary of the accounts for router pages and scripts.
+#[derive(Clone)]
+pub struct StatusServer {
+    started: SystemTime,
+    /// Whether `/status` works without WebDAV credentials
+    public: bool,
+    // used and total bytes by mount name
+    quota: MokaCache<String, Quota>,
+}
+
+impl StatusServer {
+    pub fn new(public: bool) -> Self {
+        let quota = CacheBuilder::new(100)
+            .time_to_live(Duration::from_secs(QUOTA_TTL))
+            .build();
+        Self {
+            started: SystemTime::now(),
+            public,
+            quota,
+        }
+    }
+
+    pub fn matches(&self, req: &Request<hyper::Body>) -> bool {
+        req.uri().path() == STATUS_PATH && *req.method() == Method::GET
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.public
+    }
+
+    /// Status of the given mounts, by name. Account usernames and proxies are
+    /// only shown to `authenticated` callers.
+    pub async fn handle(&self, mounts: &[(&str, &WebdavDriveFileSystem)], authenticated: bool) -> Response<Body> {
+        let mut accounts = Vec::with_capacity(mounts.len());
+        for (name, fs) in mounts {
+            accounts.push(self.account(name, fs, authenticated).await);
+        }
+        let status = Status {
+            version: env!("CARGO_PKG_VERSION"),
+            uptime: SystemTime::now()
+                .duration_since(self.started)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            accounts,
+            uploads: metrics::active_transfers(metrics::UPLOAD),
+            downloads: metrics::active_transfers(metrics::DOWNLOAD),
+        };
+        json_response(&status)
+    }
+
+    async fn account(&self, name: &str, fs: &WebdavDriveFileSystem, authenticated: bool) -> AccountStatus {
+        let (signed_in, token_expires_at) = fs.token();
+        let quota = match self.quota.get(&name.to_string()) {
+            Some(quota) => Some(quota),
+            None if signed_in => match fs.get_useage_quota().await {
+                Ok((used, total)) => {
+                    let quota = Quota { used, total };
+                    self.quota.insert(name.to_string(), quota.clone()).await;
+                    Some(quota)
+                }
+                Err(err) => {
+                    debug!(account = %name, error = %err, "status: get quota failed");
+                    None
+                }
+            },
+            None => None,
+        };
+        // the rest of a proxy url may carry credentials
+        let proxy = url::Url::parse(&fs.proxy_url())
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .filter(|_| authenticated);
+        AccountStatus {
+            name: name.to_string(),
+            username: Some(fs.username()).filter(|_| authenticated),
+            signed_in,
+            token_expires_at: token_expires_at.map(unix_time),
+            quota,
+            proxy,
+            cache: CacheStatus {
+                entries: fs.dir_cache().entry_count(),
+                persisted_bytes: fs.dir_cache().persisted_size(),
+                block_bytes: fs.block_cache().map(|cache| cache.size()),
+            },
+            pending_uploads: fs.pending_uploads(),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt::{Debug, Formatter};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use bytes::{Buf, Bytes};
+use dav_server::davpath::DavPath;
+use dav_server::fs::{DavFile, DavMetaData, FsError, FsFuture};
+use futures_util::future::FutureExt;
+
+use crate::link::{LinkSigner, LINK_PREFIX};
+use crate::model::WebdavFile;
+
+/// Top level directory of the virtual view, relative to the WebDAV root
+pub const STRM_DIR: &str = "/.strm";
+const STRM_EXTENSION: &str = ".strm";
+
+#[derive(Debug, Clone)]
+pub struct StrmConfig {
+    /// Externally reachable address of this server, e.g. `http://192.168.1.2:9867`
+    pub base_url: String,
+    pub signer: LinkSigner,
+}
+
+impl StrmConfig {
+    /// Path of the mirrored drive entry if `dav_path` lies in the virtual view.
+    pub fn target(&self, dav_path: &DavPath) -> Option<PathBuf> {
+        let path = dav_path.as_pathbuf();
+        let rel = path.strip_prefix(STRM_DIR).ok()?;
+        Some(Path::new("/").join(rel))
+    }
+
+    pub fn playback_url(&self, file_id: &str) -> String {
+        let base_url = self.base_url.trim_end_matches('/');
+        match self.signer.query(file_id) {
+            Some(query) => format!("{}{}{}?{}", base_url, LINK_PREFIX, file_id, query),
+            None => format!("{}{}{}", base_url, LINK_PREFIX, file_id),
+        }
+    }
+
+    /// Mirror a directory listing: folders as is, videos as `.strm` files, the rest hidden.
+    pub fn entries(&self, files: Vec<WebdavFile>) -> Vec<WebdavFile> {
+        files
+            .into_iter()
+            .filter_map(|file| {
+                if file.kind == "drive#folder" {
+                    Some(file)
+                } else if file.variant.is_none() && !file.id.is_empty() && file.mime_type.contains("video/") {
+                    Some(self.entry(file))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn entry(&self, mut file: WebdavFile) -> WebdavFile {
+        let stem = match file.name.rfind('.') {
+            Some(pos) if pos > 0 => file.name[..pos].to_string(),
+            _ => file.name.clone(),
+        };
+        file.name = format!("{}{}", stem, STRM_EXTENSION);
+        file.size = self.content(&file.id).len().to_string();
+        file.file_extension = STRM_EXTENSION.to_string();
+        file.mime_type = "text/plain".to_string();
+        file.medias = Vec::new();
+        file
+    }
+
+    pub fn content(&self, file_id: &str) -> String {
+        format!("{}\n", self.playback_url(file_id))
+    }
+}
+
+/// In-memory, read-only `.strm` file.
+pub struct StrmFile {
+    file: WebdavFile,
+    content: Bytes,
+    pos: usize,
+}
+
+impl StrmFile {
+    pub fn new(config: &StrmConfig, file: WebdavFile) -> Self {
+        let content = Bytes::from(config.content(&file.id));
+        Self { file, content, pos: 0 }
+    }
+}
+
+impl Debug for StrmFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StrmFile")
+            .field("file", &self.file.name)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl DavFile for StrmFile {
+    fn metadata(&'_ mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let file = self.file.clone();
+        async move { Ok(Box::new(file) as Box<dyn DavMetaData>) }.boxed()
+    }
+
+    fn write_buf(&'_ mut self, _buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
+        async move { Err(FsError::Forbidden) }.boxed()
+    }
+
+    fn write_bytes(&mut self, _buf: Bytes) -> FsFuture<()> {
+        async move { Err(FsError::Forbidden) }.boxed()
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<Bytes> {
+        async move {
+            let start = std::cmp::min(self.pos, self.content.len());
+            let end = std::cmp::min(start + count, self.content.len());
+            self.pos = end;
+            Ok(self.content.slice(start..end))
+        }
+        .boxed()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<u64> {
+        async move {
+            let len = self.content.len() as i64;
+            let new_pos = match pos {
+                SeekFrom::Start(pos) => pos as i64,
+                SeekFrom::End(pos) => len + pos,
+                SeekFrom::Current(pos) => self.pos as i64 + pos,
+            };
+            if new_pos < 0 {
+                return Err(FsError::GeneralFailure);
+            }
+            self.pos = new_pos as usize;
+            Ok(new_pos as u64)
+        }
+        .boxed()
+    }
+
+    fn flush(&mut self) -> FsFuture<()> {
+        async move { Ok(()) }.boxed()
+    }
+}
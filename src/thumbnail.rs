@@ -0,0 +1,61 @@
+use dav_server::{body::Body, davpath::DavPath};
+use hyper::{Method, Request, Response, StatusCode};
+use tracing::debug;
+use url::form_urlencoded;
+
+use crate::vfs::WebdavDriveFileSystem;
+
+/// Serves `GET <path>?thumbnail` by redirecting to the preview image PikPak
+/// generated for the file, so browsers can show previews without downloading content.
+#[derive(Clone)]
+pub struct ThumbnailServer {
+    fs: WebdavDriveFileSystem,
+}
+
+impl ThumbnailServer {
+    pub fn new(fs: WebdavDriveFileSystem) -> Self {
+        Self { fs }
+    }
+
+    pub fn matches(&self, req: &Request<hyper::Body>) -> bool {
+        (req.method() == Method::GET || req.method() == Method::HEAD)
+            && req.uri().query().map_or(false, |query| {
+                form_urlencoded::parse(query.as_bytes()).any(|(k, _)| k == "thumbnail")
+            })
+    }
+
+    pub async fn handle(&self, req: &Request<hyper::Body>) -> Response<Body> {
+        match self.serve(req).await {
+            Ok(res) => res,
+            Err(status) => Response::builder()
+                .status(status)
+                .body(Body::from(status.to_string()))
+                .unwrap(),
+        }
+    }
+
+    async fn serve(&self, req: &Request<hyper::Body>) -> Result<Response<Body>, StatusCode> {
+        let dav_path = DavPath::new(req.uri().path()).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let file = self
+            .fs
+            .lookup(&dav_path)
+            .await
+            .map_err(|_| StatusCode::NOT_FOUND)?
+            .ok_or(StatusCode::NOT_FOUND)?;
+        // folders and files without a preview fall back to their icon
+        let url = if !file.thumbnail_link.is_empty() {
+            file.thumbnail_link
+        } else if !file.icon_link.is_empty() {
+            file.icon_link
+        } else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        debug!(path = %dav_path, url = %url, "thumbnail: redirect");
+        Ok(Response::builder()
+            .status(StatusCode::FOUND)
+            .header("Location", url)
+            .header("Cache-Control", "private, max-age=300")
+            .body(Body::empty())
+            .unwrap())
+    }
+}
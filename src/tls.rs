@@ -0,0 +1,239 @@
+use std::convert::Infallible;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use hyper::body::HttpBody;
+use hyper::server::conn::Http;
+use hyper::{Request, Response, StatusCode};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info};
+
+/// How often the certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+/// Pause after a failed accept, e.g. when out of file descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Clients that don't finish the handshake in time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Hands out the current certificate, swapped whenever the files on disk change.
+struct CertResolver {
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(config: &TlsConfig) -> Result<CertifiedKey> {
+    let mut reader = BufReader::new(
+        File::open(&config.cert).with_context(|| format!("open {}", config.cert.display()))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader)
+        .with_context(|| format!("parse {}", config.cert.display()))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        bail!("no certificate found in {}", config.cert.display());
+    }
+
+    let mut reader = BufReader::new(
+        File::open(&config.key).with_context(|| format!("open {}", config.key.display()))?,
+    );
+    let key = rustls_pemfile::read_all(&mut reader)
+        .with_context(|| format!("parse {}", config.key.display()))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("no private key found in {}", config.key.display()))?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| anyhow::anyhow!("unsupported private key type in {}", config.key.display()))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Load the certificate and keep it fresh in the background.
+pub fn acceptor(config: TlsConfig) -> Result<TlsAcceptor> {
+    let resolver = Arc::new(CertResolver {
+        key: RwLock::new(Arc::new(load_certified_key(&config)?)),
+    });
+    info!(cert = %config.cert.display(), "tls: certificate loaded");
+
+    let reload_resolver = resolver.clone();
+    tokio::spawn(async move {
+        let mut last = (modified(&config.cert), modified(&config.key));
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            let current = (modified(&config.cert), modified(&config.key));
+            if current == last {
+                continue;
+            }
+            match load_certified_key(&config) {
+                Ok(key) => {
+                    *reload_resolver.key.write().unwrap() = Arc::new(key);
+                    last = current;
+                    info!(cert = %config.cert.display(), "tls: certificate reloaded");
+                }
+                // cert and key may be mid-replacement, try again next round
+                Err(err) => error!(error = %err, "tls: reload certificate failed"),
+            }
+        }
+    });
+
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Accept TLS connections on `addr` and serve each with `handler`, which also
+/// gets the client address, until `shutdown` flips, then wait for the open
+/// connections to finish their requests.
+pub async fn serve<F, Fut, B>(
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    handler: F,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()>
+where
+    F: Fn(Request<hyper::Body>, SocketAddr) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response<B>, Infallible>> + Send + 'static,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let listener = TcpListener::bind(addr).await?;
+    // every connection task holds a sender, recv() returns None once all are gone
+    let (active_tx, mut active_rx) = mpsc::channel::<()>(1);
+    loop {
+        let (stream, peer) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(conn) => conn,
+                Err(err) => {
+                    error!(error = %err, "tls: accept failed");
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => break,
+        };
+        let acceptor = acceptor.clone();
+        let handler = handler.clone();
+        let mut shutdown = shutdown.clone();
+        let active = active_tx.clone();
+        tokio::spawn(async move {
+            let _active = active;
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    debug!(peer = %peer, error = %err, "tls: handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    debug!(peer = %peer, "tls: handshake timed out");
+                    return;
+                }
+            };
+            let service = hyper::service::service_fn(move |req| handler(req, peer));
+            let conn = Http::new().serve_connection(stream, service);
+            tokio::pin!(conn);
+            let mut closing = false;
+            loop {
+                tokio::select! {
+                    res = conn.as_mut() => {
+                        if let Err(err) = res {
+                            debug!(peer = %peer, error = %err, "tls: connection error");
+                        }
+                        break;
+                    }
+                    _ = shutdown.changed(), if !closing => {
+                        closing = true;
+                        conn.as_mut().graceful_shutdown();
+                    }
+                }
+            }
+        });
+    }
+    drop(listener);
+    drop(active_tx);
+    let _ = active_rx.recv().await;
+    Ok(())
+}
+
+/// Plain HTTP listener that sends every request to the same path on `https_port`.
+pub async fn serve_redirect(addr: SocketAddr, https_port: u16) -> Result<()> {
+    let make_service = hyper::service::make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(hyper::service::service_fn(move |req: Request<hyper::Body>| async move {
+            Ok::<_, Infallible>(redirect_response(&req, https_port))
+        }))
+    });
+    info!("redirecting http on {:?} to https", addr);
+    hyper::Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+fn redirect_response(req: &Request<hyper::Body>, https_port: u16) -> Response<hyper::Body> {
+    let host = req
+        .headers()
+        .get(hyper::header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().host());
+    let host = match host {
+        Some(host) => host,
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(hyper::Body::from("Host header required"))
+                .unwrap()
+        }
+    };
+    // drop the port of the plain listener, keep IPv6 brackets
+    let host = match host.rfind(':') {
+        Some(pos) if !host[pos..].contains(']') => &host[..pos],
+        _ => host,
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, https_port, path)
+    };
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(hyper::header::LOCATION, location)
+        .body(hyper::Body::empty())
+        .unwrap()
+}
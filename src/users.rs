@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use moka::future::{Cache, CacheBuilder};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use toml::Spanned;
+use tracing::{debug, info};
+
+use crate::config;
+
+/// How long a checked password is trusted before bcrypt runs again
+const VERIFIED_TTL: u64 = 600;
+
+/// What a WebDAV user may do below their root
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permissions {
+    ReadWrite,
+    ReadOnly,
+    /// Upload and rename, but never delete or overwrite
+    NoDelete,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Self::ReadWrite
+    }
+}
+
+impl Permissions {
+    pub fn can_write(&self) -> bool {
+        *self != Self::ReadOnly
+    }
+
+    pub fn can_delete(&self) -> bool {
+        *self == Self::ReadWrite
+    }
+
+    /// What is left when both `self` and `other` apply.
+    pub fn restrict(self, other: Self) -> Self {
+        if !self.can_write() || !other.can_write() {
+            Self::ReadOnly
+        } else if !self.can_delete() || !other.can_delete() {
+            Self::NoDelete
+        } else {
+            Self::ReadWrite
+        }
+    }
+}
+
+/// An authenticated WebDAV user
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    /// Directory inside the served root the user is confined to,
+    /// starting with the account name when serving several accounts
+    pub root: String,
+    pub permissions: Permissions,
+}
+
+#[derive(Debug, Deserialize)]
+struct UsersFile {
+    #[serde(default, rename = "user")]
+    users: Vec<UserEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserEntry {
+    name: Spanned<String>,
+    /// bcrypt hash, e.g. from `htpasswd -nbB name password`
+    password: Spanned<String>,
+    #[serde(default = "default_root")]
+    root: String,
+    #[serde(default)]
+    permissions: Permissions,
+}
+
+fn default_root() -> String {
+    "/".to_string()
+}
+
+#[derive(Debug, Clone)]
+enum Secret {
+    /// `--auth-password` from the command line
+    Plain(String),
+    Bcrypt(String),
+}
+
+/// WebDAV accounts from `--auth-user`/`--auth-password` and `[[user]]` tables.
+#[derive(Clone)]
+pub struct Users {
+    users: Arc<HashMap<String, (Secret, User)>>,
+    // digests of name and password pairs that passed verification
+    verified: Cache<String, ()>,
+}
+
+impl Users {
+    pub fn new(auth: Option<(String, String)>) -> Self {
+        let mut users = HashMap::new();
+        if let Some((name, password)) = auth {
+            let user = User {
+                name: name.clone(),
+                root: default_root(),
+                permissions: Permissions::ReadWrite,
+            };
+            users.insert(name, (Secret::Plain(password), user));
+        }
+        let verified = CacheBuilder::new(1000)
+            .time_to_live(Duration::from_secs(VERIFIED_TTL))
+            .build();
+        Self {
+            users: Arc::new(users),
+            verified,
+        }
+    }
+
+    /// Add the `[[user]]` tables of a users or config file.
+    pub fn add_file(&mut self, path: &Path, content: &str) -> Result<()> {
+        let file: UsersFile =
+            toml::from_str(content).with_context(|| format!("parse {}", path.display()))?;
+        let count = file.users.len();
+        let users = Arc::make_mut(&mut self.users);
+        for entry in file.users {
+            if !entry.password.get_ref().starts_with("$2") {
+                bail!(
+                    "{} line {}: password of {} is not a bcrypt hash",
+                    path.display(),
+                    config::line_of(content, entry.password.start()),
+                    entry.name.get_ref()
+                );
+            }
+            let line = config::line_of(content, entry.name.start());
+            let name = entry.name.into_inner();
+            let user = User {
+                name: name.clone(),
+                root: entry.root,
+                permissions: entry.permissions,
+            };
+            if users
+                .insert(name.clone(), (Secret::Bcrypt(entry.password.into_inner()), user))
+                .is_some()
+            {
+                bail!("{} line {}: duplicate user {}", path.display(), line, name);
+            }
+        }
+        if count > 0 {
+            info!(path = %path.display(), users = count, "users: loaded");
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// The user `name` if `password` is theirs.
+    pub async fn authenticate(&self, name: &str, password: &str) -> Option<User> {
+        let (secret, user) = self.users.get(name)?;
+        let key = format!("{:x}", Sha1::digest(format!("{}:{}", name, password).as_bytes()));
+        if self.verified.get(&key).is_some() {
+            return Some(user.clone());
+        }
+        let ok = match secret {
+            Secret::Plain(plain) => plain == password,
+            Secret::Bcrypt(hash) => {
+                let hash = hash.clone();
+                let password = password.to_string();
+                // bcrypt is slow on purpose, keep it off the reactor
+                tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false))
+                    .await
+                    .unwrap_or(false)
+            }
+        };
+        if !ok {
+            debug!(user = %name, "users: wrong password");
+            return None;
+        }
+        self.verified.insert(key, ()).await;
+        Some(user.clone())
+    }
+}
@@ -11,10 +11,11 @@ use sha1::{Sha1, Digest};
 use hex_literal::hex;
 use base64::encode;
 use std::str::from_utf8;
-use anyhow::{Result, Context};
+use anyhow::{bail, Result, Context};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use dashmap::DashMap;
 use futures_util::future::{ready, ok, FutureExt};
+use futures_util::{StreamExt, TryStreamExt};
 use tracing::{debug, error, trace,info};
 use dav_server::{
     davpath::DavPath,
@@ -25,7 +26,18 @@ use dav_server::{
 };
 use moka::future::{Cache as AuthCache};
 use tracing_subscriber::fmt::format;
-use crate::cache::Cache;
+use crate::cache::{Cache, PersistConfig};
+use crate::block_cache::BlockCache;
+use crate::reader::StreamReader;
+use crate::media::MediaConfig;
+use crate::strm::{StrmConfig, StrmFile};
+use crate::link::{url_expires_at, LinkCache};
+use crate::props;
+use crate::checksum::{self, Checksum};
+use crate::sidecar::SidecarStore;
+use crate::users::Permissions;
+use crate::metrics::{self, Transfer};
+use ::time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use reqwest::{
     header::{HeaderMap, HeaderValue},
     StatusCode,
@@ -50,18 +62,45 @@ const REFERER: &str = "https://api-drive.mypikpak.com/drive/v1/files";
 const UA: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/92.0.4515.131 Safari/537.36";
 const UPLOAD_CHUNK_SIZE: u64 = 16 * 1024 * 1024; // 16MB
 
+/// What `reconfigure` may change while the server runs
+#[derive(Debug)]
+struct Settings {
+    credentials: Credentials,
+    proxy_url: String,
+}
+
+/// An OSS multipart upload that was started but not completed yet
+#[derive(Debug, Clone)]
+struct PendingUpload {
+    file_name: String,
+    file_id: String,
+    oss_args: OssArgs,
+}
+
 #[derive(Clone)]
 pub struct WebdavDriveFileSystem {
-    credentials:Credentials,
+    settings: Arc<std::sync::RwLock<Settings>>,
+    // of the access token from the last successful sign in
+    token_expires_at: Arc<std::sync::RwLock<Option<SystemTime>>>,
     auth_cache:AuthCache<String, String>,
     dir_cache: Cache,
     uploading: Arc<DashMap<String, Vec<WebdavFile>>>,
+    // by upload id, aborted on shutdown
+    multipart: Arc<DashMap<String, PendingUpload>>,
     root: PathBuf,
     client:reqwest::Client,
-    proxy_url:String,
     upload_buffer_size: usize,
     skip_upload_same_size: bool,
     prefer_http_download: bool,
+    block_cache: Option<BlockCache>,
+    read_ahead_size: usize,
+    download_connections: usize,
+    download_chunk_size: usize,
+    media: MediaConfig,
+    strm: Option<StrmConfig>,
+    link_cache: LinkCache,
+    sidecar: SidecarStore,
+    permissions: Permissions,
 }
 
 impl WebdavDriveFileSystem {
@@ -74,8 +113,19 @@ impl WebdavDriveFileSystem {
         upload_buffer_size: usize,
         skip_upload_same_size: bool,
         prefer_http_download: bool,
+        meta_cache: Option<PersistConfig>,
+        block_cache: Option<BlockCache>,
+        read_ahead_size: usize,
+        download_connections: usize,
+        download_chunk_size: usize,
+        media: MediaConfig,
+        strm: Option<StrmConfig>,
+        sidecar: SidecarStore,
     ) -> Result<Self> {
-        let dir_cache = Cache::new(cache_size, cache_ttl);
+        let mut dir_cache = Cache::new(cache_size, cache_ttl);
+        if let Some(config) = meta_cache {
+            dir_cache = dir_cache.persist_to(config).await;
+        }
         debug!("dir cache initialized");
         let root = if root.starts_with('/') {
             PathBuf::from(root)
@@ -97,51 +147,119 @@ impl WebdavDriveFileSystem {
         let auth_cache = AuthCache::new(2);
 
         let driver = Self {
-            credentials,
+            settings: Arc::new(std::sync::RwLock::new(Settings {
+                credentials,
+                proxy_url,
+            })),
+            token_expires_at: Arc::new(std::sync::RwLock::new(None)),
             auth_cache,
             dir_cache,
-            proxy_url,
             uploading: Arc::new(DashMap::new()),
+            multipart: Arc::new(DashMap::new()),
             root,
             client,
             upload_buffer_size,
             skip_upload_same_size,
             prefer_http_download,
+            block_cache,
+            read_ahead_size,
+            download_connections,
+            download_chunk_size,
+            media,
+            strm,
+            link_cache: LinkCache::new(10000),
+            sidecar,
+            permissions: Permissions::default(),
         };
 
         if let Err(err) = driver.update_token().await {
             error!(error = %err, "save access token failed");
         }
 
-        driver.dir_cache.invalidate_all();
-
         Ok(driver)
 
     }
 
-    async fn update_token(&self)  -> Result<()>{
+    pub fn proxy_url(&self) -> String {
+        self.settings.read().unwrap().proxy_url.clone()
+    }
+
+    pub fn username(&self) -> String {
+        self.settings.read().unwrap().credentials.username.clone()
+    }
+
+    /// Whether an access token is at hand, and until when it is valid.
+    pub fn token(&self) -> (bool, Option<SystemTime>) {
+        let signed_in = self.auth_cache.get(&"access_token".to_string()).is_some();
+        (signed_in, *self.token_expires_at.read().unwrap())
+    }
+
+    pub fn dir_cache(&self) -> &Cache {
+        &self.dir_cache
+    }
+
+    pub fn block_cache(&self) -> Option<&BlockCache> {
+        self.block_cache.as_ref()
+    }
+
+    /// Multipart uploads started and not completed yet.
+    pub fn pending_uploads(&self) -> usize {
+        self.multipart.len()
+    }
+
+    /// Switch to new credentials, proxy and directory cache parameters without a restart.
+    pub async fn reconfigure(&self, credentials: Credentials, proxy_url: String, cache_size: u64, cache_ttl: u64) {
+        let relogin = {
+            let mut settings = self.settings.write().unwrap();
+            let relogin = settings.credentials != credentials || settings.proxy_url != proxy_url;
+            settings.credentials = credentials;
+            settings.proxy_url = proxy_url;
+            relogin
+        };
+        self.dir_cache.reconfigure(cache_size, cache_ttl);
+        if relogin {
+            info!("credentials or proxy changed, signing in again");
+            self.auth_cache.invalidate(&"access_token".to_string()).await;
+            if let Err(err) = self.update_token().await {
+                error!(error = %err, "save access token failed");
+            }
+        }
+    }
+
+    async fn update_token(&self) -> Result<()> {
+        let res = self.sign_in().await;
+        metrics::token_refresh(res.is_ok());
+        res
+    }
+
+    async fn sign_in(&self)  -> Result<()>{
         let mut data = HashMap::new();
         data.insert("captcha_token", "");
         data.insert("client_id", "YNxT9w7GMdWvEOKa");
         data.insert("client_secret", "dbw2OtmVEeuUvIptb1Coyg");
-        data.insert("username", &self.credentials.username);
-        data.insert("password", &self.credentials.password);
+        let credentials = self.settings.read().unwrap().credentials.clone();
+        data.insert("username", &credentials.username);
+        data.insert("password", &credentials.password);
 
         let mut rurl = format!("https://user.mypikpak.com/v1/auth/signin");
-        if self.proxy_url.len()>4{
-            rurl = format!("{}/https://user.mypikpak.com/v1/auth/signin",&self.proxy_url);
+        if self.proxy_url().len()>4{
+            rurl = format!("{}/https://user.mypikpak.com/v1/auth/signin",&self.proxy_url());
         }
 
        let url = rurl;
-       let res = self
+       let request = self
             .client
             .post(url)
             .json(&data)
-            .send()
+            .build()?;
+       let res = self
+            .execute(request)
             .await?;
         match res.error_for_status_ref() {
             Ok(_) => {
                 let res = res.json::<RefreshTokenResponse>().await?;
+                *self.token_expires_at.write().unwrap() =
+                    Some(SystemTime::now() + Duration::from_secs(res.expires_in));
                 let access_token = "access_token".to_string();
                 self.auth_cache.insert(access_token, res.access_token).await;
             }
@@ -149,11 +267,21 @@ impl WebdavDriveFileSystem {
                 let msg = res.text().await?;
                 let context = format!("{}: {}", err, msg);
                 debug!(msg=%msg);
+                return Err(anyhow::anyhow!(context));
             }
         }
         Ok(())
     }
 
+    /// Send a PikPak or OSS API request, counting it by endpoint and status.
+    async fn execute(&self, req: reqwest::Request) -> reqwest::Result<reqwest::Response> {
+        let method = req.method().clone();
+        let url = req.url().clone();
+        let res = self.client.execute(req).await;
+        metrics::api_call(&method, &url, &res);
+        res
+    }
+
     async fn request<U>(&self, url: String) -> Result<Option<U>>
     where
         U: DeserializeOwned,
@@ -167,11 +295,13 @@ impl WebdavDriveFileSystem {
             }
         };
         let url = reqwest::Url::parse(&url)?;
-        let res = self
+        let request = self
             .client
             .get(url.clone())
             .bearer_auth(&access_token)
-            .send()
+            .build()?;
+        let res = self
+            .execute(request)
             .await?
             .error_for_status();
         match res {
@@ -204,11 +334,13 @@ impl WebdavDriveFileSystem {
                             // wait for a while and retry
                             time::sleep(Duration::from_secs(1)).await;
                         }
-                        let res = self
+                        let request = self
                             .client
                             .get(url)
                             .bearer_auth(&access_token)
-                            .send()
+                            .build()?;
+                        let res = self
+                            .execute(request)
                             .await?
                             .error_for_status()?;
                         if res.status() == StatusCode::NO_CONTENT {
@@ -239,12 +371,14 @@ impl WebdavDriveFileSystem {
             }
         };
         let url = reqwest::Url::parse(&url)?;
-        let res = self
+        let request = self
             .client
             .post(url.clone())
             .json(&req)
             .bearer_auth(&access_token)
-            .send()
+            .build()?;
+        let res = self
+            .execute(request)
             .await?
             .error_for_status();
         match res {
@@ -277,12 +411,14 @@ impl WebdavDriveFileSystem {
                             // wait for a while and retry
                             time::sleep(Duration::from_secs(1)).await;
                         }
-                        let res = self
+                        let request = self
                             .client
                             .post(url)
                             .json(&req)
                             .bearer_auth(&access_token)
-                            .send()
+                            .build()?;
+                        let res = self
+                            .execute(request)
                             .await?
                             .error_for_status()?;
                         if res.status() == StatusCode::NO_CONTENT {
@@ -311,12 +447,14 @@ impl WebdavDriveFileSystem {
             }
         };
         let url = reqwest::Url::parse(&url)?;
-        let res = self
+        let request = self
             .client
             .patch(url.clone())
             .json(&req)
             .bearer_auth(&access_token)
-            .send()
+            .build()?;
+        let res = self
+            .execute(request)
             .await?
             .error_for_status();
         match res {
@@ -349,12 +487,14 @@ impl WebdavDriveFileSystem {
                             // wait for a while and retry
                             time::sleep(Duration::from_secs(1)).await;
                         }
-                        let res = self
+                        let request = self
                             .client
                             .post(url)
                             .json(&req)
                             .bearer_auth(&access_token)
-                            .send()
+                            .build()?;
+                        let res = self
+                            .execute(request)
                             .await?
                             .error_for_status()?;
                         if res.status() == StatusCode::NO_CONTENT {
@@ -371,8 +511,8 @@ impl WebdavDriveFileSystem {
 
     async fn create_folder(&self, parent_id:&str, folder_name: &str) -> Result<WebdavFile> {
         let mut rurl = format!("https://api-drive.mypikpak.com/drive/v1/files");
-        if self.proxy_url.len()>4{
-            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files",&self.proxy_url);
+        if self.proxy_url().len()>4{
+            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files",&self.proxy_url());
         }
         let url = rurl;
         let req = CreateFolderRequest{kind:"drive#folder",name:folder_name,parent_id:parent_id};
@@ -392,8 +532,8 @@ impl WebdavDriveFileSystem {
         //let trashurl = "https://api-drive.mypikpak.com/drive/v1/files:batchTrash"    //放入回收站
         //let deleteurl = "https://api-drive.mypikpak.com/drive/v1/files:batchDelete"   //彻底删除
         let mut rurl = format!("https://api-drive.mypikpak.com/drive/v1/files:batchDelete");
-        if self.proxy_url.len()>4{
-            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files:batchDelete",&self.proxy_url);
+        if self.proxy_url().len()>4{
+            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files:batchDelete",&self.proxy_url());
         }
         let url = rurl;
         let req = DelFileRequest{ids:vec![file_id.to_string()]};
@@ -403,8 +543,8 @@ impl WebdavDriveFileSystem {
 
     pub async fn rename_file(&self, file_id: &str, new_name: &str) -> Result<()> {
         let mut rurl = format!("https://api-drive.mypikpak.com/drive/v1/files/{}",file_id);
-        if self.proxy_url.len()>4{
-            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files/{}",&self.proxy_url,file_id);
+        if self.proxy_url().len()>4{
+            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files/{}",&self.proxy_url(),file_id);
         }
         let url = rurl;
         let req = RenameFileRequest{name:new_name};
@@ -414,11 +554,20 @@ impl WebdavDriveFileSystem {
         Ok(())
     }
 
+    pub async fn update_file(&self, file_id: &str, req: &UpdateFileRequest) -> Result<()> {
+        let mut rurl = format!("https://api-drive.mypikpak.com/drive/v1/files/{}",file_id);
+        if self.proxy_url().len()>4{
+            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files/{}",&self.proxy_url(),file_id);
+        }
+        self.patch_request::<_, serde_json::Value>(rurl, req).await?;
+        Ok(())
+    }
+
 
     pub async fn move_file(&self, file_id: &str, new_parent_id: &str) -> Result<()> {
         let mut rurl = format!("https://api-drive.mypikpak.com/drive/v1/files:batchMove");
-        if self.proxy_url.len()>4{
-            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files:batchMove",&self.proxy_url);
+        if self.proxy_url().len()>4{
+            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files:batchMove",&self.proxy_url());
         }
         let url = rurl;
         let req = MoveFileRequest{ids:vec![file_id.to_string()],to:MoveTo { parent_id: new_parent_id.to_string()}};
@@ -432,8 +581,8 @@ impl WebdavDriveFileSystem {
 
     pub async fn copy_file(&self, file_id: &str, new_parent_id: &str) -> Result<()> {
         let mut rurl = format!("https://api-drive.mypikpak.com/drive/v1/files:batchCopy");
-        if self.proxy_url.len()>4{
-            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files:batchCopy",&self.proxy_url);
+        if self.proxy_url().len()>4{
+            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files:batchCopy",&self.proxy_url());
         }
         let url = rurl;
         let req = MoveFileRequest{ids:vec![file_id.to_string()],to:MoveTo { parent_id: new_parent_id.to_string()}};
@@ -447,8 +596,8 @@ impl WebdavDriveFileSystem {
 
     pub async fn get_useage_quota(&self) -> Result<(u64, u64)> {
         let mut rurl = format!("https://api-drive.mypikpak.com/drive/v1/about");
-        if self.proxy_url.len()>4{
-            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/about",&self.proxy_url);
+        if self.proxy_url().len()>4{
+            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/about",&self.proxy_url());
         }
         let url = rurl;
        
@@ -476,8 +625,8 @@ impl WebdavDriveFileSystem {
 
         loop{
             let mut rurl = format!("https://api-drive.mypikpak.com/drive/v1/files?parent_id={}&thumbnail_size=SIZE_LARGE&with_audit=true&page_token={}&limit=0&filters={{\"phase\":{{\"eq\":\"PHASE_TYPE_COMPLETE\"}},\"trashed\":{{\"eq\":false}}}}",&parent_file_id,pagetoken);
-            if self.proxy_url.len()>4{
-                rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files?parent_id={}&thumbnail_size=SIZE_LARGE&with_audit=true&page_token={}&limit=0&filters={{\"phase\":{{\"eq\":\"PHASE_TYPE_COMPLETE\"}},\"trashed\":{{\"eq\":false}}}}",&self.proxy_url,&parent_file_id,pagetoken);
+            if self.proxy_url().len()>4{
+                rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files?parent_id={}&thumbnail_size=SIZE_LARGE&with_audit=true&page_token={}&limit=0&filters={{\"phase\":{{\"eq\":\"PHASE_TYPE_COMPLETE\"}},\"trashed\":{{\"eq\":false}}}}",&self.proxy_url(),&parent_file_id,pagetoken);
             }
             let url = rurl;
 
@@ -495,6 +644,7 @@ impl WebdavDriveFileSystem {
             }
         }
 
+        self.sidecar.apply(&mut files);
         self.cache_dir(path_str,files.clone()).await;
         Ok(files)
 
@@ -519,7 +669,7 @@ impl WebdavDriveFileSystem {
                         return Some(file.clone());
                     }
                 }
-                None
+                self.media.variant_named(&files, &file_name)
             });
             Ok(file)
         } else {
@@ -531,13 +681,18 @@ impl WebdavDriveFileSystem {
     async fn read_dir_and_cache(&self, path: PathBuf) -> Result<Vec<WebdavFile>, FsError> {
         let path_str = path.to_string_lossy().into_owned();
         debug!(path = %path_str, "read_dir and cache");
-        let parent_file_id = if path_str == "/" {
+        // whether the id came from the persisted path index and may be stale
+        let mut indexed = false;
+        let mut parent_file_id = if path_str == "/" {
             "".to_string()
         } else {
             match self.find_in_cache(&path) {
                 Ok(Some(file)) => file.id,
                 _ => {
-                    if let Ok(Some(file)) = self.get_by_path(&path_str).await {
+                    if let Some(file_id) = self.dir_cache.lookup_id(&path_str) {
+                        indexed = true;
+                        file_id
+                    } else if let Ok(Some(file)) = self.get_by_path(&path_str).await {
                         file.id
                     } else {
                         return Err(FsError::NotFound);
@@ -547,8 +702,35 @@ impl WebdavDriveFileSystem {
         };
         let mut files = if let Some(files) = self.dir_cache.get(&path_str) {
             files
+        } else if let Some((files, stale)) = self.dir_cache.get_persisted(&path_str) {
+            if stale {
+                // serve the persisted listing now and refresh it in the background
+                debug!(path = %path_str, "revalidate persisted dir listing");
+                let fs = self.clone();
+                let path_str = path_str.clone();
+                let parent_file_id = parent_file_id.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = fs.list_files_and_cache(path_str.clone(), parent_file_id).await {
+                        error!(path = %path_str, error = %err, "revalidate dir listing failed");
+                    }
+                });
+            }
+            self.dir_cache.promote(path_str.clone(), files.clone()).await;
+            files
         } else {
-            self.list_files_and_cache(path_str, parent_file_id.clone()).await.map_err(|_| FsError::NotFound)?
+            match self.list_files_and_cache(path_str.clone(), parent_file_id.clone()).await {
+                Ok(files) => files,
+                Err(err) if indexed => {
+                    debug!(path = %path_str, file_id = %parent_file_id, error = %err, "indexed file id failed, resolve path again");
+                    self.dir_cache.forget_id(&path_str);
+                    parent_file_id = match self.get_by_path(&path_str).await {
+                        Ok(Some(file)) if !file.id.is_empty() => file.id,
+                        _ => return Err(FsError::NotFound),
+                    };
+                    self.list_files_and_cache(path_str, parent_file_id.clone()).await.map_err(|_| FsError::NotFound)?
+                }
+                Err(_) => return Err(FsError::NotFound),
+            }
         };
 
         let uploading_files = self.list_uploading_files(&parent_file_id);
@@ -557,6 +739,12 @@ impl WebdavDriveFileSystem {
             files.extend(uploading_files);
         }
 
+        let variants = self.media.variants_of(&files);
+        if !variants.is_empty() {
+            debug!("added {} transcoded variants", variants.len());
+            files.extend(variants);
+        }
+
         Ok(files)
     }
 
@@ -654,23 +842,145 @@ impl WebdavDriveFileSystem {
 
     }
 
-    async fn get_download_url(&self,file_id: &str) -> Result<String> {
-        debug!("get_download_url");
+    async fn get_file_detail(&self, file_id: &str) -> Result<WebdavFile> {
         let mut rurl = format!("https://api-drive.mypikpak.com/drive/v1/files/{}",file_id.to_string());
-        if self.proxy_url.len()>4{
-            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files/{}",&self.proxy_url,file_id.to_string());
+        if self.proxy_url().len()>4{
+            rurl = format!("{}/https://api-drive.mypikpak.com/drive/v1/files/{}",&self.proxy_url(),file_id.to_string());
         }
 
         let url = rurl;
         let res: WebdavFile = self.request(url)
             .await?
             .context("expect response")?;
-        if(res.mime_type.contains("video/")){
-            Ok(res.medias[0].link.url.clone())
-        }else{
-            Ok(res.web_content_link.clone())
+        Ok(res)
+    }
+
+    pub async fn get_download_url(&self,file_id: &str, variant: Option<&str>) -> Result<String> {
+        debug!("get_download_url");
+        let key = match variant {
+            Some(label) => format!("{}#{}", file_id, label),
+            None => file_id.to_string(),
+        };
+        if let Some(url) = self.link_cache.get(&key) {
+            return Ok(url);
+        }
+        let res = self.get_file_detail(file_id).await?;
+        let download_url = match variant {
+            Some(label) => self.media.variant_url(&res, label),
+            None => self.media.select_url(&res),
+        };
+        let download_url = download_url.context("no download url available")?;
+        self.link_cache.insert(key, download_url.clone()).await;
+        Ok(download_url)
+    }
+
+    /// Keep the medias of a file in its cached listing, with the transcode sizes resolved so far.
+    async fn remember_medias(&self, dir: &Path, file_id: &str, medias: Vec<Media>) {
+        let key = dir.to_string_lossy().into_owned();
+        if let Some(mut files) = self.dir_cache.get(&key) {
+            if let Some(file) = files.iter_mut().find(|f| f.id == file_id) {
+                file.medias = medias;
+                self.dir_cache.insert(key, files).await;
+            }
+        }
+    }
+
+    /// Url of a transcoded stream regardless of the media policy, used for HLS.
+    pub async fn get_transcode_url(&self, file_id: &str, quality: Option<&str>) -> Result<String> {
+        let res = self.get_file_detail(file_id).await?;
+        self.media.transcode_url(&res, quality).context("no transcoded stream available")
+    }
+
+    /// Keep `mtime` as the modification time of the file at `dav_path`, e.g. right after its upload.
+    pub async fn record_mtime(&self, dav_path: &DavPath, mtime: SystemTime) -> Result<(), FsError> {
+        if self.strm_target(dav_path).is_some() {
+            return Err(FsError::Forbidden);
+        }
+        let path = self.normalize_dav_path(dav_path);
+        debug!(path = %path.display(), "fs: record mtime");
+        // a finished upload only shows up with its id in a fresh listing
+        self.dir_cache.invalidate_parent(&path).await;
+        let file = self.get_file(path.clone()).await?.ok_or(FsError::NotFound)?;
+        if file.id.is_empty() || file.variant.is_some() {
+            return Err(FsError::Forbidden);
+        }
+        self.sidecar.set_mtime(&file.id, mtime).await;
+        self.dir_cache.invalidate_parent(&path).await;
+        Ok(())
+    }
+
+    /// Whether reading `file` serves its own content, which its hash and checksums
+    /// describe, rather than a transcode picked by the media policy.
+    pub async fn serves_original(&self, file: &WebdavFile) -> bool {
+        if file.variant.is_some() {
+            return false;
+        }
+        if !self.media.may_transcode(file) {
+            return true;
+        }
+        match self.get_file_detail(&file.id).await {
+            Ok(detail) => self.media.selects_original(&detail),
+            // can't tell, don't vouch for the content
+            Err(_) => false,
+        }
+    }
+
+    /// Checksums known for the content at `dav_path`, answered as `OC-Checksum` on downloads.
+    pub async fn checksums(&self, dav_path: &DavPath) -> Vec<Checksum> {
+        if self.strm_target(dav_path).is_some() {
+            return Vec::new();
+        }
+        let path = self.normalize_dav_path(dav_path);
+        match self.get_file(path).await {
+            Ok(Some(file)) if self.serves_original(&file).await => {
+                checksum::known(&file, self.sidecar.sha1(&file.id))
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Drive entry whose properties PROPFIND reports, `.strm` entries included.
+    async fn prop_file(&self, strm_target: Option<PathBuf>, path: PathBuf) -> Result<WebdavFile, FsError> {
+        match strm_target {
+            Some(target) => self.strm_file(target).await,
+            None => self.get_file(path).await?.ok_or(FsError::NotFound),
         }
+    }
 
+    /// Resolve a request path, relative to the WebDAV root, to a drive file.
+    pub async fn lookup(&self, dav_path: &DavPath) -> Result<Option<WebdavFile>, FsError> {
+        let path = self.normalize_dav_path(dav_path);
+        self.get_file(path).await
+    }
+
+    pub async fn fetch(&self, url: &str) -> Result<reqwest::Response> {
+        let res = self.client
+            .get(url)
+            .timeout(Duration::from_secs(120))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(res)
+    }
+
+    /// Total size of the content behind `url`, used for transcodes whose size the listing doesn't know.
+    pub async fn content_length(&self, url: &str) -> Result<u64> {
+        let res = self.client
+            .get(url)
+            .header(RANGE, "bytes=0-0")
+            .send()
+            .await?
+            .error_for_status()?;
+        let total = res
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit('/').next())
+            .and_then(|v| v.parse::<u64>().ok());
+        match total {
+            Some(total) => Ok(total),
+            None => res.content_length().context("unknown content length"),
+        }
     }
 
 
@@ -689,11 +999,37 @@ impl WebdavDriveFileSystem {
 
     }
 
+    /// Split a large range into `download_chunk_size` pieces fetched over
+    /// `download_connections` concurrent requests, reassembled in order.
+    /// Only the last piece may come back short, at the end of the file.
+    pub async fn download_parallel(&self, url: &str, start_pos: u64, size: usize) -> Result<Bytes> {
+        let chunk_size = self.download_chunk_size;
+        let ranges: Vec<(u64, usize)> = (0..size)
+            .step_by(chunk_size)
+            .map(|offset| (start_pos + offset as u64, std::cmp::min(chunk_size, size - offset)))
+            .collect();
+        debug!(url = %url, start = start_pos, size = size, parts = ranges.len(), "download file in parallel");
+        let parts: Vec<Bytes> = futures_util::stream::iter(ranges.clone())
+            .map(|(pos, len)| self.download(url, pos, len))
+            .buffered(self.download_connections)
+            .try_collect()
+            .await?;
+        let last = parts.len().saturating_sub(1);
+        let mut content = BytesMut::with_capacity(size);
+        for (i, (part, (pos, len))) in parts.into_iter().zip(ranges).enumerate() {
+            if part.len() > len || (part.len() < len && i != last) {
+                bail!("range at {} returned {} bytes instead of {}", pos, part.len(), len);
+            }
+            content.extend_from_slice(&part);
+        }
+        Ok(content.freeze())
+    }
+
 
     pub async fn create_file_with_proof(&self,name: &str, parent_file_id: &str, hash:&str, size: u64,chunk_count: u64) ->  Result<UploadResponse> {
         let mut url = format!("https://api-drive.mypikpak.com/drive/v1/files");
-        if self.proxy_url.len()>4{
-            url = format!("{}/https://api-drive.mypikpak.com/drive/v1/files",&self.proxy_url);
+        if self.proxy_url().len()>4{
+            url = format!("{}/https://api-drive.mypikpak.com/drive/v1/files",&self.proxy_url());
         }
         let req = UploadRequest{
             kind:"drive#file".to_string(),
@@ -714,13 +1050,13 @@ impl WebdavDriveFileSystem {
             }
         };
 
-        let res = self.client.post(url)
+        let request = self.client.post(url)
             .header(reqwest::header::CONTENT_LENGTH, payload.len())
             .header(reqwest::header::HOST, "api-drive.mypikpak.com")
             .header(reqwest::header::AUTHORIZATION, format!("Bearer {}",access_token))
             .body(payload)
-            .send()
-            .await?;
+            .build()?;
+        let res = self.execute(request).await?;
 
         let body = &res.text().await?;
         let result = match serde_json::from_str::<UploadResponse>(body) {
@@ -737,8 +1073,8 @@ impl WebdavDriveFileSystem {
 
     pub async fn get_pre_upload_info(&self,oss_args:&OssArgs) -> Result<String> {
         let mut url = format!("https://{}/{}?uploads",oss_args.endpoint,oss_args.key);
-        if self.proxy_url.len()>4{
-            url = format!("{}/https://{}/{}?uploads",&self.proxy_url,oss_args.endpoint,oss_args.key);
+        if self.proxy_url().len()>4{
+            url = format!("{}/https://{}/{}?uploads",&self.proxy_url(),oss_args.endpoint,oss_args.key);
         }
         let now = SystemTime::now();
         let gmt = httpdate::fmt_http_date(now);
@@ -751,7 +1087,7 @@ impl WebdavDriveFileSystem {
         let oss_header = format!("OSS {}:{}",&oss_args.access_key_id,&oss_sign);
         let header_auth = HeaderValue::from_str(&oss_header).unwrap();
         req.headers_mut().insert(reqwest::header::AUTHORIZATION, header_auth);
-        let res = self.client.execute(req).await?;
+        let res = self.execute(req).await?;
         let body = &res.text().await?;
 
         let result: InitiateMultipartUploadResult = from_str(body).unwrap();
@@ -766,8 +1102,8 @@ impl WebdavDriveFileSystem {
         .finish();
 
         let mut url = format!("https://{}/{}?{}",oss_args.endpoint,oss_args.key,encoded);
-        if self.proxy_url.len()>4{
-            url = format!("{}/https://{}/{}?{}",&self.proxy_url,oss_args.endpoint,oss_args.key,encoded);
+        if self.proxy_url().len()>4{
+            url = format!("{}/https://{}/{}?{}",&self.proxy_url(),oss_args.endpoint,oss_args.key,encoded);
         }
   
         let now = SystemTime::now();
@@ -781,7 +1117,7 @@ impl WebdavDriveFileSystem {
         let oss_header = format!("OSS {}:{}",&oss_args.access_key_id,&oss_sign);
         let header_auth = HeaderValue::from_str(&oss_header).unwrap();
         req.headers_mut().insert(reqwest::header::AUTHORIZATION, header_auth);
-        let res = self.client.execute(req).await?;
+        let res = self.execute(req).await?;
         //let body = &res.text().await?;
 
         let etag  = match &res.headers().get("ETag") {
@@ -812,11 +1148,66 @@ impl WebdavDriveFileSystem {
         let oss_header = format!("OSS {}:{}",&oss_args.access_key_id,&oss_sign);
         let header_auth = HeaderValue::from_str(&oss_header).unwrap();
         req.headers_mut().insert(reqwest::header::AUTHORIZATION, header_auth);
-        let res = self.client.execute(req).await?;
+        let res = self.execute(req).await?;
 
         Ok(())
     }
 
+    pub async fn abort_upload(&self, oss_args:&OssArgs, upload_id:&str) -> Result<()> {
+        let encoded: String = form_urlencoded::Serializer::new(String::new())
+        .append_pair("uploadId", upload_id)
+        .finish();
+        let mut url = format!("https://{}/{}?{}",oss_args.endpoint,oss_args.key,encoded);
+        if self.proxy_url().len()>4{
+            url = format!("{}/https://{}/{}?{}",&self.proxy_url(),oss_args.endpoint,oss_args.key,encoded);
+        }
+        let now = SystemTime::now();
+        let gmt = httpdate::fmt_http_date(now);
+        let mut req = self.client.delete(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .header("X-Oss-Security-Token", &oss_args.security_token)
+            .header("Date", &gmt).build()?;
+        let oss_sign:String = self.hmac_authorization(&req,&gmt,oss_args);
+        let oss_header = format!("OSS {}:{}",&oss_args.access_key_id,&oss_sign);
+        let header_auth = HeaderValue::from_str(&oss_header).unwrap();
+        req.headers_mut().insert(reqwest::header::AUTHORIZATION, header_auth);
+        self.execute(req).await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Abort a multipart upload that won't be completed and drop its placeholder file.
+    async fn abandon_upload(&self, upload_id: &str) {
+        let upload = match self.multipart.remove(upload_id) {
+            Some((_, upload)) => upload,
+            None => return,
+        };
+        info!(file_name = %upload.file_name, upload_id = %upload_id, "abort unfinished upload");
+        if let Err(err) = self.abort_upload(&upload.oss_args, upload_id).await {
+            error!(file_name = %upload.file_name, error = %err, "abort upload failed");
+        }
+        if !upload.file_id.is_empty() {
+            if let Err(err) = self.remove_file(&upload.file_id).await {
+                error!(file_name = %upload.file_name, error = %err, "remove unfinished file failed");
+            }
+        }
+    }
+
+    /// Abort the multipart uploads still in progress, dropping their placeholder
+    /// files, and write the persisted directory listings to disk.
+    pub async fn shutdown(&self) {
+        let pending = self
+            .multipart
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        for upload_id in pending {
+            self.abandon_upload(&upload_id).await;
+        }
+        if let Err(err) = self.dir_cache.flush().await {
+            error!(error = %err, "cache: flush metadata failed");
+        }
+    }
+
 
 
 
@@ -857,6 +1248,39 @@ impl WebdavDriveFileSystem {
     }
    
 
+    /// Drive path mirrored by `dav_path` when it lies in the `.strm` view.
+    fn strm_target(&self, dav_path: &DavPath) -> Option<PathBuf> {
+        let target = self.strm.as_ref()?.target(dav_path)?;
+        let rel_path = target.strip_prefix("/").unwrap_or(&target);
+        if rel_path == Path::new("") {
+            return Some(self.root.clone());
+        }
+        Some(self.root.join(rel_path))
+    }
+
+    async fn strm_read_dir(&self, path: PathBuf) -> Result<Vec<WebdavFile>, FsError> {
+        let strm = self.strm.as_ref().ok_or(FsError::NotFound)?;
+        let files = self.read_dir_and_cache(path).await?;
+        Ok(strm.entries(files))
+    }
+
+    async fn strm_file(&self, path: PathBuf) -> Result<WebdavFile, FsError> {
+        let parent = match path.parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => return Ok(WebdavFile::new_root()),
+        };
+        let name = path
+            .file_name()
+            .ok_or(FsError::NotFound)?
+            .to_string_lossy()
+            .into_owned();
+        self.strm_read_dir(parent)
+            .await?
+            .into_iter()
+            .find(|file| file.name == name)
+            .ok_or(FsError::NotFound)
+    }
+
     fn normalize_dav_path(&self, dav_path: &DavPath) -> PathBuf {
         let path = dav_path.as_pathbuf();
         if self.root.parent().is_none() || path.starts_with(&self.root) {
@@ -868,6 +1292,27 @@ impl WebdavDriveFileSystem {
         }
         self.root.join(rel_path)
     }
+
+    /// View of the drive for a WebDAV user confined to `root` below ours,
+    /// never allowing more than we do.
+    pub fn for_user(&self, root: &str, permissions: Permissions) -> Self {
+        let mut fs = self.clone();
+        let root = root.trim_matches('/');
+        if !root.is_empty() {
+            fs.root = self.root.join(root);
+        }
+        fs.permissions = self.permissions.restrict(permissions);
+        fs
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        self.permissions
+    }
+
+    /// Drive folder this view of the drive is confined to.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
 }
 
 impl DavFileSystem for WebdavDriveFileSystem {
@@ -876,6 +1321,21 @@ impl DavFileSystem for WebdavDriveFileSystem {
         dav_path: &'a DavPath,
         options: OpenOptions,
     ) -> FsFuture<Box<dyn DavFile>> {
+        if options.write && !self.permissions.can_write() {
+            return ready(Err(FsError::Forbidden)).boxed();
+        }
+        if let Some(target) = self.strm_target(dav_path) {
+            debug!(path = %target.display(), "fs: open strm");
+            return async move {
+                if options.write {
+                    return Err(FsError::Forbidden);
+                }
+                let file = self.strm_file(target).await?;
+                let strm = self.strm.as_ref().ok_or(FsError::NotFound)?;
+                Ok(Box::new(StrmFile::new(strm, file)) as Box<dyn DavFile>)
+            }
+            .boxed();
+        }
         let path = self.normalize_dav_path(dav_path);
         let mode = if options.write { "write" } else { "read" };
         debug!(path = %path.display(), mode = %mode, "fs: open");
@@ -890,24 +1350,36 @@ impl DavFileSystem for WebdavDriveFileSystem {
                 .get_file(parent_path.to_path_buf())
                 .await?
                 .ok_or(FsError::NotFound)?;
-            let sha1 = options.checksum.and_then(|c| {
-                if let Some((algo, hash)) = c.split_once(':') {
-                    if algo.eq_ignore_ascii_case("sha1") {
-                        Some(hash.to_string())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            });
+            let checksums = options
+                .checksum
+                .as_deref()
+                .map(Checksum::parse_list)
+                .unwrap_or_default();
 
 
             let dav_file = if let Some(mut file) = self.get_file(path.clone()).await? {
                 if options.write && options.create_new {
                     return Err(FsError::Exists);
                 }
-                AliyunDavFile::new(self.clone(), file, parent_file.id,parent_path.to_path_buf(),options.size.unwrap_or_default(),sha1)
+                if options.write && !self.permissions.can_delete() {
+                    // overwriting throws the old content away
+                    return Err(FsError::Forbidden);
+                }
+                if file.variant.is_some() {
+                    // virtual transcode entries are read-only
+                    if options.write {
+                        return Err(FsError::Forbidden);
+                    }
+                    let mut dav_file = AliyunDavFile::new(self.clone(), file, parent_file.id,parent_path.to_path_buf(),0,Vec::new());
+                    dav_file.resolve_variant_size().await?;
+                    dav_file
+                } else {
+                    if !options.write {
+                        // GET tags the response with the metadata of the opened file
+                        file.transcoded = !self.serves_original(&file).await;
+                    }
+                    AliyunDavFile::new(self.clone(), file, parent_file.id,parent_path.to_path_buf(),options.size.unwrap_or_default(),checksums)
+                }
             } else if options.write && (options.create || options.create_new) {
 
 
@@ -944,11 +1416,22 @@ impl DavFileSystem for WebdavDriveFileSystem {
                     web_content_link: "".to_string(),
                     medias:Vec::new(),
                     hash:Some(file_hash),
+                    thumbnail_link: "".to_string(),
+                    icon_link: "".to_string(),
+                    md5_checksum: "".to_string(),
+                    revision: "".to_string(),
+                    starred: false,
+                    description: "".to_string(),
+                    tags: Vec::new(),
+                    audit: None,
+                    share_status: "".to_string(),
+                    variant: None,
+                    transcoded: false,
                 };
                 let mut uploading = self.uploading.entry(parent_file.id.clone()).or_default();
                 uploading.push(file.clone());
 
-                AliyunDavFile::new(self.clone(), file, parent_file.id,parent_path.to_path_buf(),size.unwrap_or(0),sha1)
+                AliyunDavFile::new(self.clone(), file, parent_file.id,parent_path.to_path_buf(),size.unwrap_or(0),checksums)
             } else {
                 println!("FsError::NotFound");
                 return Err(FsError::NotFound);
@@ -963,10 +1446,14 @@ impl DavFileSystem for WebdavDriveFileSystem {
         path: &'a DavPath,
         _meta: ReadDirMeta,
     ) -> FsFuture<FsStream<Box<dyn DavDirEntry>>> {
+        let strm_target = self.strm_target(path);
         let path = self.normalize_dav_path(path);
         debug!(path = %path.display(), "fs: read_dir");
         async move {
-            let files = self.read_dir_and_cache(path.clone()).await?;
+            let files = match strm_target {
+                Some(target) => self.strm_read_dir(target).await?,
+                None => self.read_dir_and_cache(path.clone()).await?,
+            };
             let mut v: Vec<Box<dyn DavDirEntry>> = Vec::with_capacity(files.len());
             for file in files {
                 v.push(Box::new(file));
@@ -981,6 +1468,9 @@ impl DavFileSystem for WebdavDriveFileSystem {
 
 
     fn create_dir<'a>(&'a self, dav_path: &'a DavPath) -> FsFuture<()> {
+        if self.strm_target(dav_path).is_some() || !self.permissions.can_write() {
+            return ready(Err(FsError::Forbidden)).boxed();
+        }
         let path = self.normalize_dav_path(dav_path);
         async move {
             let parent_path = path.parent().ok_or(FsError::NotFound)?;
@@ -1006,6 +1496,9 @@ impl DavFileSystem for WebdavDriveFileSystem {
 
 
     fn remove_dir<'a>(&'a self, dav_path: &'a DavPath) -> FsFuture<()> {
+        if self.strm_target(dav_path).is_some() || !self.permissions.can_delete() {
+            return ready(Err(FsError::Forbidden)).boxed();
+        }
         let path = self.normalize_dav_path(dav_path);
         debug!(path = %path.display(), "fs: remove_dir");
         async move {
@@ -1014,6 +1507,9 @@ impl DavFileSystem for WebdavDriveFileSystem {
                 .get_file(path.clone())
                 .await?
                 .ok_or(FsError::NotFound)?;
+            if file.variant.is_some() {
+                return Err(FsError::Forbidden);
+            }
 
             if !(file.kind==String::from("drive#folder")) {
                 return Err(FsError::Forbidden);
@@ -1025,6 +1521,7 @@ impl DavFileSystem for WebdavDriveFileSystem {
                     error!(path = %path.display(), error = %err, "remove directory failed");
                     FsError::GeneralFailure
                 })?;
+            self.sidecar.remove(&file.id).await;
             self.dir_cache.invalidate(&path).await;
             self.dir_cache.invalidate_parent(&path).await;
             Ok(())
@@ -1034,6 +1531,9 @@ impl DavFileSystem for WebdavDriveFileSystem {
 
 
     fn remove_file<'a>(&'a self, dav_path: &'a DavPath) -> FsFuture<()> {
+        if self.strm_target(dav_path).is_some() || !self.permissions.can_delete() {
+            return ready(Err(FsError::Forbidden)).boxed();
+        }
         let path = self.normalize_dav_path(dav_path);
         debug!(path = %path.display(), "fs: remove_file");
         async move {
@@ -1041,6 +1541,9 @@ impl DavFileSystem for WebdavDriveFileSystem {
                 .get_file(path.clone())
                 .await?
                 .ok_or(FsError::NotFound)?;
+            if file.variant.is_some() {
+                return Err(FsError::Forbidden);
+            }
 
             self.remove_file(&file.id)
                 .await
@@ -1048,6 +1551,7 @@ impl DavFileSystem for WebdavDriveFileSystem {
                     error!(path = %path.display(), error = %err, "remove file failed");
                     FsError::GeneralFailure
                 })?;
+            self.sidecar.remove(&file.id).await;
             self.dir_cache.invalidate_parent(&path).await;
             Ok(())
         }
@@ -1056,6 +1560,12 @@ impl DavFileSystem for WebdavDriveFileSystem {
 
 
     fn rename<'a>(&'a self, from_dav: &'a DavPath, to_dav: &'a DavPath) -> FsFuture<()> {
+        if self.strm_target(from_dav).is_some()
+            || self.strm_target(to_dav).is_some()
+            || !self.permissions.can_write()
+        {
+            return ready(Err(FsError::Forbidden)).boxed();
+        }
         let from = self.normalize_dav_path(from_dav);
         let to = self.normalize_dav_path(to_dav);
         debug!(from = %from.display(), to = %to.display(), "fs: rename");
@@ -1068,6 +1578,9 @@ impl DavFileSystem for WebdavDriveFileSystem {
                         .get_file(from.clone())
                         .await?
                         .ok_or(FsError::NotFound)?;
+                    if file.variant.is_some() {
+                        return Err(FsError::Forbidden);
+                    }
                     is_dir = if file.kind == "drive#folder" {
                             true
                         } else {
@@ -1084,6 +1597,9 @@ impl DavFileSystem for WebdavDriveFileSystem {
                     .get_file(from.clone())
                     .await?
                     .ok_or(FsError::NotFound)?;
+                if file.variant.is_some() {
+                    return Err(FsError::Forbidden);
+                }
                 is_dir = if file.kind == "drive#folder" {
                     true
                 } else {
@@ -1110,6 +1626,12 @@ impl DavFileSystem for WebdavDriveFileSystem {
 
 
     fn copy<'a>(&'a self, from_dav: &'a DavPath, to_dav: &'a DavPath) -> FsFuture<()> {
+        if self.strm_target(from_dav).is_some()
+            || self.strm_target(to_dav).is_some()
+            || !self.permissions.can_write()
+        {
+            return ready(Err(FsError::Forbidden)).boxed();
+        }
         let from = self.normalize_dav_path(from_dav);
         let to = self.normalize_dav_path(to_dav);
         debug!(from = %from.display(), to = %to.display(), "fs: copy");
@@ -1118,6 +1640,9 @@ impl DavFileSystem for WebdavDriveFileSystem {
                 .get_file(from.clone())
                 .await?
                 .ok_or(FsError::NotFound)?;
+            if file.variant.is_some() {
+                return Err(FsError::Forbidden);
+            }
             let to_parent_file = self
                 .get_file(to.parent().unwrap().to_path_buf())
                 .await?
@@ -1145,10 +1670,14 @@ impl DavFileSystem for WebdavDriveFileSystem {
     }
 
     fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
+        let strm_target = self.strm_target(path);
         let path = self.normalize_dav_path(path);
         debug!(path = %path.display(), "fs: metadata");
         async move {
-            let file = self.get_file(path).await?.ok_or(FsError::NotFound)?;
+            let file = match strm_target {
+                Some(target) => self.strm_file(target).await?,
+                None => self.get_file(path).await?.ok_or(FsError::NotFound)?,
+            };
             Ok(Box::new(file) as Box<dyn DavMetaData>)
         }
         .boxed()
@@ -1162,7 +1691,84 @@ impl DavFileSystem for WebdavDriveFileSystem {
         Box::pin(ready(true))
     }
 
+    fn get_props<'a>(&'a self, dav_path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        let strm_target = self.strm_target(dav_path);
+        let path = self.normalize_dav_path(dav_path);
+        debug!(path = %path.display(), do_content = do_content, "fs: get_props");
+        async move {
+            let file = self.prop_file(strm_target, path).await?;
+            Ok(props::pikpak_props(&file, do_content))
+        }
+        .boxed()
+    }
+
+    fn set_modified<'a>(&'a self, dav_path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        if !self.permissions.can_write() {
+            return ready(Err(FsError::Forbidden)).boxed();
+        }
+        async move { self.record_mtime(dav_path, tm).await }.boxed()
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        dav_path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>> {
+        let strm_target = self.strm_target(dav_path);
+        let path = self.normalize_dav_path(dav_path);
+        debug!(path = %path.display(), count = patch.len(), "fs: patch_props");
+        async move {
+            if strm_target.is_some() {
+                // the mirrored view is read-only
+                return Ok(patch
+                    .into_iter()
+                    .map(|(_, prop)| (StatusCode::FORBIDDEN, prop))
+                    .collect());
+            }
+            let file = self.get_file(path.clone()).await?.ok_or(FsError::NotFound)?;
+            let mut req = UpdateFileRequest::default();
+            let mut ret = Vec::with_capacity(patch.len());
+            for (set, prop) in patch {
+                let status = if file.variant.is_some() || file.id.is_empty() || !self.permissions.can_write() {
+                    StatusCode::FORBIDDEN
+                } else {
+                    props::apply_patch(&mut req, set, &prop)
+                };
+                ret.push((status, prop));
+            }
+            // PROPPATCH is all or nothing
+            if ret.iter().any(|(status, _)| *status != StatusCode::OK) {
+                return Ok(ret
+                    .into_iter()
+                    .map(|(status, prop)| match status {
+                        StatusCode::OK => (StatusCode::FAILED_DEPENDENCY, prop),
+                        _ => (status, prop),
+                    })
+                    .collect());
+            }
+            if !req.is_empty() {
+                if let Err(err) = self.update_file(&file.id, &req).await {
+                    error!(path = %path.display(), error = %err, "update file failed");
+                    return Ok(ret
+                        .into_iter()
+                        .map(|(_, prop)| (StatusCode::BAD_GATEWAY, prop))
+                        .collect());
+                }
+                if let Some(mtime) = req.modified_time.as_ref() {
+                    // PikPak may keep its own time, the store has the final say
+                    if let Ok(mtime) = OffsetDateTime::parse(mtime, &Rfc3339) {
+                        self.sidecar.set_mtime(&file.id, mtime.into()).await;
+                    }
+                }
+                self.dir_cache.invalidate_parent(&path).await;
+            }
+            Ok(ret)
+        }
+        .boxed()
+    }
+
     fn get_prop(&self, dav_path: &DavPath, prop:DavProp) -> FsFuture<Vec<u8>> {
+        let strm_target = self.strm_target(dav_path);
         let path = self.normalize_dav_path(dav_path);
         let prop_name = match prop.prefix.as_ref() {
             Some(prefix) => format!("{}:{}", prefix, prop.name),
@@ -1170,21 +1776,18 @@ impl DavFileSystem for WebdavDriveFileSystem {
         };
         debug!(path = %path.display(), prop = %prop_name, "fs: get_prop");
         async move {
-            if prop.namespace.as_deref() == Some("http://owncloud.org/ns")
-                && prop.name == "checksums"
-            {
-                let file = self.get_file(path).await?.ok_or(FsError::NotFound)?;
-                if let Some(sha1) = file.hash {
-                    let xml = format!(
-                        r#"<?xml version="1.0"?>
-                        <oc:checksums xmlns:d="DAV:" xmlns:nc="http://nextcloud.org/ns" xmlns:oc="http://owncloud.org/ns">
-                            <oc:checksum>sha1:{}</oc:checksum>
-                        </oc:checksums>
-                    "#,
-                        sha1
-                    );
-                    return Ok(xml.into_bytes());
+            if checksum::is_checksums_prop(prop.namespace.as_deref(), &prop.name) {
+                let file = self.get_file(path.clone()).await?.ok_or(FsError::NotFound)?;
+                let checksums = checksum::known(&file, self.sidecar.sha1(&file.id));
+                if checksums.is_empty() {
+                    return Err(FsError::NotFound);
                 }
+                return Ok(checksum::checksums_xml(&checksums));
+            }
+            if props::is_pikpak(&prop) {
+                let file = self.prop_file(strm_target, path).await?;
+                let value = props::pikpak_prop(&file, &prop.name).ok_or(FsError::NotFound)?;
+                return Ok(props::prop_xml(&prop.name, &value));
             }
             Err(FsError::NotImplemented)
         }
@@ -1205,7 +1808,10 @@ struct UploadState {
     chunk: u64,
     upload_id: String,
     oss_args: Option<OssArgs>,
-    sha1: Option<String>,
+    /// Checksums the client declared for the content
+    checksums: Vec<Checksum>,
+    /// Id PikPak assigned to the file being uploaded
+    file_id: String,
     upload_tags:CompleteMultipartUpload,
 }
 
@@ -1219,13 +1825,13 @@ impl Default for UploadState {
             chunk: 1,
             upload_id: String::new(),
             oss_args: None,
-            sha1: None,
+            checksums: Vec::new(),
+            file_id: String::new(),
             upload_tags: upload_tags,
         }
     }
 }
 
-#[derive(Clone)]
 struct AliyunDavFile {
     fs: WebdavDriveFileSystem,
     file: WebdavFile,
@@ -1233,7 +1839,10 @@ struct AliyunDavFile {
     parent_dir: PathBuf,
     current_pos: u64,
     download_url: Option<String>,
+    reader: Option<StreamReader>,
     upload_state: UploadState,
+    // set once content starts to flow
+    transfer: Option<Transfer>,
 }
 
 impl Debug for AliyunDavFile {
@@ -1247,8 +1856,22 @@ impl Debug for AliyunDavFile {
     }
 }
 
+impl Drop for AliyunDavFile {
+    /// A client that went away mid upload leaves the multipart upload to abort.
+    fn drop(&mut self) {
+        let upload_id = std::mem::take(&mut self.upload_state.upload_id);
+        if upload_id.is_empty() || !self.fs.multipart.contains_key(&upload_id) {
+            return;
+        }
+        let fs = self.fs.clone();
+        tokio::spawn(async move {
+            fs.abandon_upload(&upload_id).await;
+        });
+    }
+}
+
 impl AliyunDavFile {
-    fn new(fs: WebdavDriveFileSystem, file: WebdavFile, parent_file_id: String,parent_dir: PathBuf,size: u64,sha1: Option<String>,) -> Self {
+    fn new(fs: WebdavDriveFileSystem, file: WebdavFile, parent_file_id: String,parent_dir: PathBuf,size: u64,checksums: Vec<Checksum>,) -> Self {
         Self {
             fs,
             file,
@@ -1257,33 +1880,170 @@ impl AliyunDavFile {
             current_pos: 0,
             upload_state: UploadState {
                 size,
-                sha1,
+                checksums,
                 ..Default::default()
             },
             download_url: None,
+            reader: None,
+            transfer: None,
         }
     }
 
     async fn get_download_url(&self) -> Result<String, FsError> {
-        self.fs.get_download_url(&self.file.id).await.map_err(|err| {
+        self.fs.get_download_url(&self.file.id, self.file.variant.as_deref()).await.map_err(|err| {
             error!(file_id = %self.file.id, file_name = %self.file.name, error = %err, "get download url failed");
             FsError::GeneralFailure
         })
     }
 
+    /// Download url of this file, fetching a new one when missing or expired.
+    async fn download_url(&mut self) -> Result<String, FsError> {
+        if let Some(url) = self.download_url.as_ref() {
+            if !is_url_expired(url) {
+                return Ok(url.clone());
+            }
+            debug!(url = %url, "download url expired");
+        }
+        let url = self.get_download_url().await?;
+        self.download_url = Some(url.clone());
+        Ok(url)
+    }
+
+    /// Serve a read from the open stream, only reissuing a Range request after a seek.
+    async fn read_streaming(&mut self, count: usize) -> Result<Bytes, FsError> {
+        let window = self.fs.read_ahead_size as u64;
+        if let Some(reader) = self.reader.as_mut() {
+            let pos = reader.pos();
+            if self.current_pos < pos || self.current_pos - pos > window {
+                debug!(file_name = %self.file.name, from = pos, to = self.current_pos, "seek outside read-ahead window");
+                self.reader = None;
+            } else if self.current_pos > pos && reader.skip(self.current_pos - pos).await.is_err() {
+                self.reader = None;
+            }
+        }
+        let mut retried = false;
+        loop {
+            if self.reader.is_none() {
+                let download_url = self.download_url().await?;
+                let reader = StreamReader::open(&self.fs.client, &download_url, self.current_pos, self.fs.read_ahead_size)
+                    .await
+                    .map_err(|err| {
+                        error!(url = %download_url, error = %err, "open download stream failed");
+                        FsError::NotFound
+                    })?;
+                self.reader = Some(reader);
+            }
+            match self.reader.as_mut().unwrap().read(count).await {
+                Ok(content) => return Ok(content),
+                Err(err) if !retried => {
+                    debug!(file_name = %self.file.name, error = %err, "download stream broken, reopen");
+                    self.reader = None;
+                    retried = true;
+                }
+                Err(err) => {
+                    error!(file_name = %self.file.name, error = %err, "download stream failed");
+                    self.reader = None;
+                    return Err(FsError::NotFound);
+                }
+            }
+        }
+    }
+
+    /// Size of a virtual transcode entry, from the medias of its file or else its stream.
+    /// It is kept in the cached listing, later opens don't need to ask again.
+    async fn resolve_variant_size(&mut self) -> Result<(), FsError> {
+        let label = match self.file.variant.clone() {
+            Some(label) => label,
+            None => return Ok(()),
+        };
+        if let Some(size) = self.fs.media.variant_size(&self.file, &label) {
+            self.file.size = size.to_string();
+            return Ok(());
+        }
+        let mut detail = self.fs.get_file_detail(&self.file.id).await.map_err(|err| {
+            error!(file_id = %self.file.id, error = %err, "get file detail failed");
+            FsError::NotFound
+        })?;
+        let size = match self.fs.media.variant_size(&detail, &label) {
+            Some(size) => size,
+            None => {
+                let download_url = self.download_url().await?;
+                self.fs.content_length(&download_url).await.map_err(|err| {
+                    error!(url = %download_url, error = %err, "get transcoded stream size failed");
+                    FsError::NotFound
+                })?
+            }
+        };
+        self.file.size = size.to_string();
+        if let Some(media) = detail.medias.iter_mut().find(|m| m.label().eq_ignore_ascii_case(&label)) {
+            media.size = Some(size);
+        }
+        self.fs.remember_medias(&self.parent_dir, &self.file.id, detail.medias).await;
+        Ok(())
+    }
+
+    /// Block cache key, transcodes share the id of the original file.
+    fn cache_key(&self) -> String {
+        match self.file.variant.as_ref() {
+            Some(label) => format!("{}_{}", self.file.id, label.replace(|c: char| !c.is_ascii_alphanumeric(), "_")),
+            None => self.file.id.clone(),
+        }
+    }
+
+    /// Serve a read from the block cache, downloading whole blocks on a miss.
+    async fn read_through_cache(&mut self, block_cache: &BlockCache, count: usize) -> Result<Bytes, FsError> {
+        let size = self.file.size.parse::<u64>().unwrap_or(0);
+        let block_size = block_cache.block_size();
+        let end = std::cmp::min(self.current_pos + count as u64, size);
+        let mut buf = BytesMut::with_capacity(end.saturating_sub(self.current_pos) as usize);
+        let mut pos = self.current_pos;
+        let cache_key = self.cache_key();
+        while pos < end {
+            let block = pos / block_size;
+            let block_start = block * block_size;
+            let content = match block_cache.get(&cache_key, block).await {
+                Some(content) => content,
+                None => {
+                    let download_url = self.download_url().await?;
+                    let len = std::cmp::min(block_size, size - block_start) as usize;
+                    let content = self
+                        .fs
+                        .download(&download_url, block_start, len)
+                        .await
+                        .map_err(|err| {
+                            error!(url = %download_url, error = %err, "download file failed");
+                            FsError::NotFound
+                        })?;
+                    if content.len() != len {
+                        // cached, it would cut every later read of the block short
+                        error!(url = %download_url, block = block, expected = len, got = content.len(), "download returned a short block");
+                        return Err(FsError::GeneralFailure);
+                    }
+                    block_cache.put(&cache_key, block, &content).await;
+                    content
+                }
+            };
+            let offset = (pos - block_start) as usize;
+            if offset >= content.len() {
+                break;
+            }
+            let len = std::cmp::min(content.len() - offset, (end - pos) as usize);
+            buf.extend_from_slice(&content[offset..offset + len]);
+            pos += len as u64;
+        }
+        Ok(buf.freeze())
+    }
+
     async fn prepare_for_upload(&mut self) -> Result<bool, FsError> {
         if self.upload_state.chunk_count == 0 {
             let size = self.upload_state.size;
             debug!(file_name = %self.file.name, size = size, "prepare for upload");
 
             if !self.file.id.is_empty() {
-                if let Some(content_hash) = self.file.hash.as_ref() {
-                    if let Some(sha1) = self.upload_state.sha1.as_ref() {
-                        if content_hash.eq_ignore_ascii_case(sha1) {
-                            debug!(file_name = %self.file.name, sha1 = %sha1, "skip uploading same content hash file");
-                            return Ok(false);
-                        }
-                    }
+                let known = checksum::known(&self.file, self.fs.sidecar.sha1(&self.file.id));
+                if checksum::matches(&known, &self.upload_state.checksums) {
+                    debug!(file_name = %self.file.name, checksums = ?self.upload_state.checksums, "skip uploading same checksum file");
+                    return Ok(false);
                 }
 
                 if self.fs.skip_upload_same_size && self.file.size.parse::<u64>().unwrap() == size {
@@ -1320,6 +2080,7 @@ impl AliyunDavFile {
                         return Ok(false);
                     }
                 };
+                self.upload_state.file_id = upload_response.file.id.clone();
 
                 let oss_args = OssArgs {
                     bucket: upload_response.resumable.params.bucket.to_string(),
@@ -1346,6 +2107,14 @@ impl AliyunDavFile {
                     }
                 };
                 debug!(file_name = %self.file.name, upload_id = %self.upload_state.upload_id, "pre upload info get upload_id success");
+                self.fs.multipart.insert(
+                    self.upload_state.upload_id.clone(),
+                    PendingUpload {
+                        file_name: self.file.name.clone(),
+                        file_id: self.upload_state.file_id.clone(),
+                        oss_args: oss_args.clone(),
+                    },
+                );
             }
         }
         Ok(true)
@@ -1387,6 +2156,7 @@ impl AliyunDavFile {
                 Ok(part) => part,
                 Err(err) => {
                     error!(file_name = %self.file.name, error = %err, "上传分片失败，无法获取ETag");
+                    self.fs.abandon_upload(&self.upload_state.upload_id).await;
                     return Err(FsError::GeneralFailure);
                 }
             };
@@ -1406,7 +2176,22 @@ impl AliyunDavFile {
                 let mut ser = XmlSerializer::with_root(Writer::new_with_indent(&mut buffer, b' ', 4), Some("CompleteMultipartUpload"));
                 self.upload_state.upload_tags.serialize(&mut ser).unwrap();
                 let upload_tags = String::from_utf8(buffer).unwrap();
-                self.fs.complete_upload(&self.file,upload_tags,oss_args,&self.upload_state.upload_id).await;
+                if let Err(err) = self.fs.complete_upload(&self.file,upload_tags,oss_args,&self.upload_state.upload_id).await {
+                    error!(file_name = %self.file.name, error = %err, "complete upload failed");
+                    self.fs.abandon_upload(&self.upload_state.upload_id).await;
+                    return Err(FsError::GeneralFailure);
+                }
+                self.fs.multipart.remove(&self.upload_state.upload_id);
+                let sha1 = self
+                    .upload_state
+                    .checksums
+                    .iter()
+                    .find(|c| c.algorithm == checksum::Algorithm::Sha1);
+                if let Some(sha1) = sha1 {
+                    if !self.upload_state.file_id.is_empty() {
+                        self.fs.sidecar.set_sha1(&self.upload_state.file_id, &sha1.value).await;
+                    }
+                }
                 self.upload_state = UploadState::default();
                 // self.upload_state.buffer.clear();
                 // self.upload_state.chunk = 0;
@@ -1441,6 +2226,8 @@ impl DavFile for AliyunDavFile {
         debug!(file_id = %self.file.id, file_name = %self.file.name, "file: write_buf");
         async move {
             if self.prepare_for_upload().await? {
+                self.transfer.get_or_insert_with(|| Transfer::start(metrics::UPLOAD));
+                metrics::transferred(metrics::UPLOAD, buf.remaining());
                 self.upload_state.buffer.put(buf);
                 self.maybe_upload_chunk(false).await?;
             }
@@ -1453,6 +2240,8 @@ impl DavFile for AliyunDavFile {
         debug!(file_id = %self.file.id, file_name = %self.file.name, "file: write_bytes");
         async move {
             if self.prepare_for_upload().await? {
+                self.transfer.get_or_insert_with(|| Transfer::start(metrics::UPLOAD));
+                metrics::transferred(metrics::UPLOAD, buf.len());
                 self.upload_state.buffer.extend_from_slice(&buf);
                 self.maybe_upload_chunk(false).await?;
             }
@@ -1487,27 +2276,35 @@ impl DavFile for AliyunDavFile {
                 // upload in progress
                 return Err(FsError::NotFound);
             }
-            let download_url = self.download_url.take();
-            let download_url = if let Some(mut url) = download_url {
-                if is_url_expired(&url) {
-                    debug!(url = %url, "download url expired");
-                    url = self.get_download_url().await?;
-                }
-                url
+            self.transfer.get_or_insert_with(|| Transfer::start(metrics::DOWNLOAD));
+            let content = if let Some(block_cache) = self.fs.block_cache.clone() {
+                self.read_through_cache(&block_cache, count).await?
+            } else if self.fs.download_connections > 1 && count > self.fs.download_chunk_size {
+                self.reader = None;
+                let download_url = self.download_url().await?;
+                let size = self.file.size.parse::<u64>().unwrap_or(0);
+                let count = std::cmp::min(count as u64, size.saturating_sub(self.current_pos)) as usize;
+                self.fs
+                    .download_parallel(&download_url, self.current_pos, count)
+                    .await
+                    .map_err(|err| {
+                        error!(url = %download_url, error = %err, "download file failed");
+                        FsError::NotFound
+                    })?
+            } else if self.fs.read_ahead_size > 0 {
+                self.read_streaming(count).await?
             } else {
-                self.get_download_url().await?
+                let download_url = self.download_url().await?;
+                self.fs
+                    .download(&download_url, self.current_pos, count)
+                    .await
+                    .map_err(|err| {
+                        error!(url = %download_url, error = %err, "download file failed");
+                        FsError::NotFound
+                    })?
             };
-
-            let content = self
-                .fs
-                .download(&download_url, self.current_pos, count)
-                .await
-                .map_err(|err| {
-                    error!(url = %download_url, error = %err, "download file failed");
-                    FsError::NotFound
-                })?;
             self.current_pos += content.len() as u64;
-            self.download_url = Some(download_url);
+            metrics::transferred(metrics::DOWNLOAD, content.len());
             Ok(content)
         }
         .boxed()
@@ -1532,31 +2329,29 @@ impl DavFile for AliyunDavFile {
         .boxed()
     }
 
+    fn redirect_url(&mut self) -> FsFuture<Option<String>> {
+        debug!(file_id = %self.file.id, file_name = %self.file.name, "file: redirect_url");
+        async move {
+            if self.file.id.is_empty() {
+                // upload in progress
+                return Ok(None);
+            }
+            Ok(Some(self.download_url().await?))
+        }
+        .boxed()
+    }
+
    
 }
 
 fn is_url_expired(url: &str) -> bool {
-    if let Ok(oss_url) = ::url::Url::parse(url) {
-        let expires = oss_url.query_pairs().find_map(|(k, v)| {
-            if k == "x-oss-expires" {
-                if let Ok(expires) = v.parse::<u64>() {
-                    return Some(expires);
-                }
-            }
-            None
-        });
-        if let Some(expires) = expires {
-            let current_ts = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_secs();
-            // 预留 1s
-            return current_ts >= expires - 1;
-        }
+    if let Some(expires) = url_expires_at(url) {
+        let current_ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        // 预留 1s
+        return current_ts >= expires - 1;
     }
     false
 }
-
-
-
-